@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::Row;
+
+use super::{binder, ActionContext, ActionHandler, ActionOutcome};
+
+/// How many of the latest payments a lookup reports the dates of; amounts are left out, since
+/// nothing ties the requester to the account they name
+const RECENT_PAYMENTS: i64 = 5;
+
+/// Looks up the Plus status, expiry and recent payments of an account, for the model to answer with
+pub struct AccountLookup;
+
+#[derive(Deserialize)]
+struct AccountLookupArgs {
+    username: String,
+}
+
+#[async_trait]
+impl ActionHandler for AccountLookup {
+    fn name(&self) -> &'static str {
+        "account_lookup"
+    }
+
+    fn description(&self) -> &'static str {
+        "look up whether an account has Plus, when its Plus expires, whether it renews automatically and its latest payments. Use this when a user asks about their Plus expiry, their subscription or whether their payment went through, and has told you their username. Only look up the username the user says is their own. Payment amounts are not reported."
+    }
+
+    fn args_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "username": {"type": "string", "description": "username of the account to look up"}
+            },
+            "required": ["username"]
+        })
+    }
+
+    async fn execute(&self, _ctx: &ActionContext, args: Value) -> anyhow::Result<ActionOutcome> {
+        let AccountLookupArgs { username } = serde_json::from_value(args)?;
+        let mut conn = binder::connect().await?;
+        let Some(id) = binder::user_id(&mut conn, &username).await? else {
+            return Ok(ActionOutcome::success(
+                format!("There is no account with the username {username}."),
+                0,
+            ));
+        };
+
+        let subscription =
+            sqlx::query("select plan::text, expires::text from subscriptions where id = $1")
+                .bind(id)
+                .fetch_optional(&mut conn)
+                .await?;
+        let mut message = match subscription {
+            Some(row) => format!(
+                "{username} has Plus (plan {}), which expires at {} UTC.",
+                row.get::<Option<String>, _>(0).unwrap_or_default(),
+                row.get::<Option<String>, _>(1).unwrap_or_default()
+            ),
+            None => format!("{username} does not have Plus."),
+        };
+
+        let recurring_subs: i64 =
+            sqlx::query("select count(*) from recurring_subs where user_id = $1")
+                .bind(id)
+                .fetch_one(&mut conn)
+                .await?
+                .get(0);
+        message += if recurring_subs > 0 {
+            " It renews automatically."
+        } else {
+            " It does not renew automatically."
+        };
+
+        let payments = sqlx::query(
+            "select created::text from payments where user_id = $1 order by created desc limit $2",
+        )
+        .bind(id)
+        .bind(RECENT_PAYMENTS)
+        .fetch_all(&mut conn)
+        .await?;
+        if payments.is_empty() {
+            message += " There are no payments on record.";
+        } else {
+            message += " Latest payments were made at:";
+            for row in payments {
+                message += &format!(
+                    "\n- {} UTC",
+                    row.get::<Option<String>, _>(0).unwrap_or_default()
+                );
+            }
+        }
+        Ok(ActionOutcome::success(message, 0))
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::{
+    database::Approval,
+    responder::{notify, Requester},
+    telegram::send_approval_card,
+    CONFIG, DB,
+};
+
+use super::{audit, execute_audited, ActionContext, ActionHandler, ACTIONS};
+
+/// What the user is told while an action waits for the admin
+pub const AWAITING_APPROVAL_REPLY: &str = "Thank you! Your request has been passed on to our team for approval. We will get back to you here as soon as it has been reviewed.";
+
+/// What the user is told when the admin rejects an action
+const REJECTED_REPLY: &str = "Sorry, our team reviewed your request and could not approve it. Please reply if you have any questions.";
+
+/// What the user is told when the admin approves an action that was disabled in the meantime
+const DISABLED_REPLY: &str = "Our team approved your request, but it can no longer be carried out automatically. A human will look into it and get back to you.";
+
+/// How many of the latest messages in the conversation the approval card shows
+const EXCERPT_MESSAGES: usize = 6;
+
+/// How much of each message the approval card shows
+const EXCERPT_CHARS: usize = 300;
+
+/// Stores an action for the admin to decide on, and posts its card to the admin's Telegram chat
+pub(super) async fn request_approval(
+    ctx: &ActionContext,
+    handler: &dyn ActionHandler,
+    args: Value,
+) -> anyhow::Result<()> {
+    let admin_chat_id = CONFIG
+        .telegram_config
+        .as_ref()
+        .and_then(|config| config.admin_chat_id)
+        .context("approving actions needs telegram_config.admin_chat_id")?;
+    let approval_id = DB
+        .insert_approval(&Approval {
+            convo_id: ctx.convo_id,
+            action: handler.name().to_owned(),
+            args: args.clone(),
+            requester: serde_json::to_value(&ctx.requester)?,
+            model: ctx.model.clone(),
+            ..Default::default()
+        })
+        .await?;
+    audit(ctx, handler.name(), &args, "awaiting_approval", None).await?;
+    log::info!(
+        "convo {} needs approval #{approval_id} for {} with {}",
+        ctx.convo_id,
+        handler.name(),
+        args
+    );
+
+    let mut excerpt = DB.get_convo_history(ctx.convo_id).await?;
+    if !ctx.user_text.is_empty() {
+        excerpt.push(("user".to_owned(), ctx.user_text.clone()));
+    }
+    let excerpt = excerpt[excerpt.len().saturating_sub(EXCERPT_MESSAGES)..]
+        .iter()
+        .map(|(role, text)| format!("{role}: {}", truncate(text, EXCERPT_CHARS)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let card = format!(
+        "Approval #{approval_id}: {} requested by {} ({}) in convo {}\n\n{}\n\nArgs:\n{}\n\nConversation:\n{excerpt}",
+        handler.name(),
+        ctx.requester.name,
+        ctx.requester.platform,
+        ctx.convo_id,
+        handler.summary(&args),
+        serde_json::to_string_pretty(&args)?,
+    );
+    send_approval_card(admin_chat_id, approval_id, &card).await
+}
+
+/// Carries out the admin's decision on an action awaiting approval, and queues a message telling the
+/// user how it went. Returns a summary for the admin.
+pub async fn decide_approval(
+    approval_id: i64,
+    approved: bool,
+    admin: &str,
+) -> anyhow::Result<String> {
+    let Some(approval) = DB.decide_approval(approval_id, approved, admin).await? else {
+        return Ok(format!("Approval #{approval_id} was already decided."));
+    };
+    let requester: Requester = serde_json::from_value(approval.requester)?;
+    let ctx = &ActionContext {
+        convo_id: approval.convo_id,
+        requester,
+        model: approval.model,
+        user_text: String::new(),
+    };
+    let decision = if approved { "approved" } else { "rejected" };
+    audit(
+        ctx,
+        &approval.action,
+        &approval.args,
+        &format!("{decision} by {admin}"),
+        None,
+    )
+    .await?;
+    log::info!("{admin} {decision} approval #{}", approval.approval_id);
+    if !approved {
+        notify(&ctx.requester, ctx.convo_id, REJECTED_REPLY).await?;
+        return Ok(format!("Rejected by {admin}."));
+    }
+
+    let Some(handler) = ACTIONS.get(&approval.action) else {
+        // the decision is taken, so the user has to hear about it now
+        notify(&ctx.requester, ctx.convo_id, DISABLED_REPLY).await?;
+        return Ok(format!(
+            "Approved by {admin}, but not performed: action {} is no longer enabled.",
+            approval.action
+        ));
+    };
+    let (reply, summary) = match execute_audited(ctx, handler, approval.args).await {
+        Ok(outcome) if outcome.success => (
+            format!(
+                "Good news: our team approved your request. {}",
+                outcome.message
+            ),
+            format!("Approved by {admin}: {}", outcome.message),
+        ),
+        Ok(outcome) => (
+            outcome.message.clone(),
+            format!(
+                "Approved by {admin}, but not performed: {}",
+                outcome.message
+            ),
+        ),
+        Err(err) => {
+            log::error!("approved action {} failed: {:?}", approval.action, err);
+            (
+                "Our team approved your request, but something went wrong carrying it out. A human will look into it and get back to you.".to_owned(),
+                format!("Approved by {admin}, but failed: {err}"),
+            )
+        }
+    };
+    notify(&ctx.requester, ctx.convo_id, &reply).await?;
+    Ok(summary)
+}
+
+/// The first `max_chars` characters of a text, with an ellipsis if it was longer
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
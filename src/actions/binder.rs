@@ -0,0 +1,19 @@
+use sqlx::{Connection, PgConnection, Row};
+
+use crate::CONFIG;
+
+/// Connects to the binder database configured in `ActionsConfig`
+pub async fn connect() -> anyhow::Result<PgConnection> {
+    let conn = PgConnection::connect(&CONFIG.actions_config.as_ref().unwrap().binder_db).await?;
+    log::debug!("connected to binder!");
+    Ok(conn)
+}
+
+/// Looks up the id of a binder user
+pub async fn user_id(conn: &mut PgConnection, uname: &str) -> anyhow::Result<Option<i64>> {
+    let row = sqlx::query("select id::bigint from users_legacy where username = $1")
+        .bind(uname)
+        .fetch_optional(conn)
+        .await?;
+    Ok(row.map(|row| row.get(0)))
+}
@@ -0,0 +1,34 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::{database::ConvoStatus, DB};
+
+use super::{ActionContext, ActionHandler, ActionOutcome};
+
+/// Hands the conversation over to a human on the support team
+pub struct Escalate;
+
+#[async_trait]
+impl ActionHandler for Escalate {
+    fn name(&self) -> &'static str {
+        "escalate"
+    }
+
+    fn description(&self) -> &'static str {
+        "hand the conversation over to a human on the support team. Use this when the user explicitly asks for a human, or when you cannot solve the problem and it needs staff attention. Once it is done, tell the user that a human will follow up."
+    }
+
+    fn args_schema(&self) -> Value {
+        json!({"type": "object", "properties": {}})
+    }
+
+    async fn execute(&self, ctx: &ActionContext, _args: Value) -> anyhow::Result<ActionOutcome> {
+        log::warn!("convo {} escalated to a human", ctx.convo_id);
+        DB.set_convo_status(ctx.convo_id, ConvoStatus::Escalated)
+            .await?;
+        Ok(ActionOutcome::success(
+            "The conversation was handed over to the support team.".to_owned(),
+            1,
+        ))
+    }
+}
@@ -0,0 +1,611 @@
+mod account_lookup;
+mod approval;
+mod binder;
+mod escalate;
+mod rate_limit;
+mod transfer_plus;
+
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    database::{AuditEntry, ConvoStatus},
+    responder::{ReplyTarget, Requester},
+    ActionSettings, CONFIG, DB,
+};
+
+use self::{
+    account_lookup::AccountLookup, escalate::Escalate, rate_limit::RateLimit,
+    transfer_plus::TransferPlus,
+};
+
+pub use self::approval::{decide_approval, truncate};
+
+/// What the model answers with when actions are enabled
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AiResponse {
+    pub action: String,
+    #[serde(default)]
+    pub args: Value,
+    pub text: String,
+}
+
+/// The action the model picks to simply reply to the user
+pub const NO_ACTION: &str = "none";
+
+/// The action the model picks to not reply at all
+pub const ABORT: &str = "abort";
+
+/// What the user is told when an action in dry-run mode would have been performed
+const DRY_RUN_REPLY: &str = "Thank you! Your request has been passed on to our team, and a human will review it and get back to you soon.";
+
+/// How long the user has to confirm a destructive action, unless configured otherwise
+const DEFAULT_CONFIRMATION_TIMEOUT_SECS: i64 = 600;
+
+/// Words that confirm a pending action, in the languages our users write in
+const CONFIRMATIONS: &[&str] = &[
+    "yes",
+    "y",
+    "yeah",
+    "yep",
+    "confirm",
+    "是",
+    "是的",
+    "确认",
+    "確認",
+    "对",
+    "對",
+    "好",
+    "بله",
+    "آره",
+    "تایید",
+];
+
+/// Words that may follow a yes without making it any less of one
+const POLITE_WORDS: &[&str] = &[
+    "please",
+    "pls",
+    "do",
+    "it",
+    "go",
+    "ahead",
+    "ok",
+    "okay",
+    "sure",
+    "thanks",
+    "thank",
+    "you",
+    "谢谢",
+    "謝謝",
+    "请",
+    "請",
+    "لطفا",
+    "ممنون",
+    "مرسی",
+];
+
+/// What an action needs to know about the conversation it is performed in
+pub struct ActionContext {
+    pub convo_id: i64,
+    pub requester: Requester,
+    /// the model that decided on the action, once there is one
+    pub model: Option<String>,
+    /// the user's message the action was asked for in response to, if there is one
+    pub user_text: String,
+}
+
+/// What an action did
+#[derive(Clone, Debug)]
+pub struct ActionOutcome {
+    /// whether the action did what was asked of it
+    pub success: bool,
+    /// what happened, in words fit for the user
+    pub message: String,
+    pub rows_affected: u64,
+    /// the prior state needed to undo the action, if it can be undone
+    pub undo: Option<Value>,
+}
+
+impl ActionOutcome {
+    pub fn success(message: String, rows_affected: u64) -> Self {
+        Self {
+            success: true,
+            message,
+            rows_affected,
+            undo: None,
+        }
+    }
+
+    /// Makes the action undoable with `ActionHandler::undo`, given this prior state
+    pub fn with_undo(mut self, undo: Value) -> Self {
+        self.undo = Some(undo);
+        self
+    }
+
+    /// The action checked the request and refused to carry it out
+    pub fn failure(message: String) -> Self {
+        Self {
+            success: false,
+            message,
+            rows_affected: 0,
+            undo: None,
+        }
+    }
+}
+
+/// An action the model can decide to perform while responding to a user
+#[async_trait]
+pub trait ActionHandler: Send + Sync {
+    /// The name the model uses to invoke the action, also its key in `ActionsConfig`
+    fn name(&self) -> &'static str;
+
+    /// Tells the model what the action does and when (not) to use it
+    fn description(&self) -> &'static str;
+
+    /// JSON schema of the action's `args`
+    fn args_schema(&self) -> Value;
+
+    /// Performs the action with the args the model gave
+    async fn execute(&self, ctx: &ActionContext, args: Value) -> anyhow::Result<ActionOutcome>;
+
+    /// Validates the args, with read-only queries at most, and describes what `execute` would do
+    async fn dry_run(&self, _ctx: &ActionContext, args: Value) -> anyhow::Result<ActionOutcome> {
+        Ok(ActionOutcome::success(
+            format!("Would perform {} with {}.", self.name(), args),
+            0,
+        ))
+    }
+
+    /// Reverts a performed action, given the prior state it recorded in its outcome
+    async fn undo(&self, _undo: Value) -> anyhow::Result<ActionOutcome> {
+        anyhow::bail!("{} cannot be undone", self.name())
+    }
+
+    /// Whether the user must explicitly confirm the action before it runs
+    fn destructive(&self) -> bool {
+        false
+    }
+
+    /// Describes to the user what the action is about to do, as a question
+    fn summary(&self, args: &Value) -> String {
+        format!("Perform {} with {}?", self.name(), args)
+    }
+}
+
+/// What became of an action the model asked for
+pub enum Invocation {
+    /// The action was performed; unless it failed, the model's reply stands
+    Performed(ActionOutcome),
+    /// The action waits for the user to confirm it; this question replaces the model's reply
+    NeedsConfirmation(String),
+    /// The action waits for the admin to approve it; this replaces the model's reply
+    NeedsApproval(String),
+}
+
+/// Performs an action the model asked for, unless it has to be confirmed by the user first
+pub async fn invoke(
+    ctx: &ActionContext,
+    handler: &dyn ActionHandler,
+    args: Value,
+) -> anyhow::Result<Invocation> {
+    if let Some(limit) = rate_limit::check_rate_limit(ctx, handler.name()).await? {
+        let (RateLimit::Cooldown(reason)
+        | RateLimit::RepeatedCooldown(reason)
+        | RateLimit::Global(reason)) = &limit;
+        audit(
+            ctx,
+            handler.name(),
+            &args,
+            &format!("rate_limited: {reason}"),
+            None,
+        )
+        .await?;
+        let message = match limit {
+            // the user only has to wait
+            RateLimit::Cooldown(reason) => format!(
+                "The action was not performed, because {reason}. The user can ask again later."
+            ),
+            RateLimit::RepeatedCooldown(reason) | RateLimit::Global(reason) => {
+                log::warn!(
+                    "convo {} escalated after hitting a rate limit: {reason}",
+                    ctx.convo_id
+                );
+                DB.set_convo_status(ctx.convo_id, ConvoStatus::Escalated)
+                    .await?;
+                format!("The action was not performed, because {reason}. The conversation was handed over to a human on the support team, who will follow up.")
+            }
+        };
+        return Ok(Invocation::Performed(ActionOutcome::failure(message)));
+    }
+    if handler.destructive() {
+        let timeout_secs = CONFIG
+            .actions_config
+            .as_ref()
+            .unwrap()
+            .confirmation_timeout_secs
+            .unwrap_or(DEFAULT_CONFIRMATION_TIMEOUT_SECS);
+        DB.insert_pending_action(
+            ctx.convo_id,
+            &ctx.requester.id,
+            handler.name(),
+            &args,
+            ctx.model.as_deref(),
+            timeout_secs,
+        )
+        .await?;
+        audit(ctx, handler.name(), &args, "awaiting_confirmation", None).await?;
+        log::info!(
+            "convo {} must confirm {} with {}",
+            ctx.convo_id,
+            handler.name(),
+            args
+        );
+        return Ok(Invocation::NeedsConfirmation(format!(
+            "{} Reply YES within {} minutes to confirm.",
+            handler.summary(&args),
+            timeout_secs / 60
+        )));
+    }
+    perform(ctx, handler, args).await
+}
+
+/// Performs an action that needs no confirmation from the user (anymore), unless the admin has to
+/// approve it first
+async fn perform(
+    ctx: &ActionContext,
+    handler: &dyn ActionHandler,
+    args: Value,
+) -> anyhow::Result<Invocation> {
+    if ACTIONS.requires_approval(handler.name()) {
+        approval::request_approval(ctx, handler, args).await?;
+        return Ok(Invocation::NeedsApproval(
+            approval::AWAITING_APPROVAL_REPLY.to_owned(),
+        ));
+    }
+    let outcome = execute_audited(ctx, handler, args).await?;
+    Ok(Invocation::Performed(outcome))
+}
+
+/// Performs the action the requester was asked to confirm in the conversation if their message
+/// confirms it, and cancels it otherwise. Returns a note telling the model what happened, if
+/// anything did.
+pub async fn run_confirmed_action(
+    requester: &Requester,
+    convo_id: i64,
+    user_text: &str,
+) -> anyhow::Result<Option<String>> {
+    let Some((name, args, model)) = DB.take_pending_action(convo_id, &requester.id).await? else {
+        return Ok(None);
+    };
+    let ctx = &ActionContext {
+        convo_id,
+        requester: requester.clone(),
+        model,
+        user_text: user_text.to_owned(),
+    };
+    if !is_confirmation(user_text, &message_prefix(requester)) {
+        log::info!("convo {} did not confirm {name}", ctx.convo_id);
+        return Ok(None);
+    }
+    let Some(handler) = ACTIONS.get(&name) else {
+        log::warn!("confirmed action {name} is no longer enabled");
+        return Ok(None);
+    };
+    let note = match perform(ctx, handler, args).await {
+        Ok(Invocation::NeedsApproval(_)) => {
+            format!("The user confirmed, and the {name} action was sent to our team for approval. Tell them a human will review it and get back to them here, and do not perform it again.")
+        }
+        Ok(Invocation::Performed(outcome)) if outcome.success => {
+            format!("The user confirmed, and the {name} action was performed successfully: {} Tell them it is done, and do not perform it again.", outcome.message)
+        }
+        Ok(Invocation::Performed(outcome)) => {
+            format!("The user confirmed, but the {name} action was not performed: {} Explain this to them, and do not try it again with the same args.", outcome.message)
+        }
+        Ok(Invocation::NeedsConfirmation(_)) => unreachable!("perform never asks to confirm"),
+        Err(err) => {
+            log::error!("confirmed action {name} failed: {:?}", err);
+            format!("The user confirmed, but the {name} action failed with this error: {err}. Apologize, tell them a human will look into it, and do not try it again.")
+        }
+    };
+    Ok(Some(note))
+}
+
+/// Performs an action and records the result in the audit log. Actions in dry-run mode only record
+/// what they would have done, and come out as unsuccessful, pending human review.
+async fn execute_audited(
+    ctx: &ActionContext,
+    handler: &dyn ActionHandler,
+    args: Value,
+) -> anyhow::Result<ActionOutcome> {
+    if ACTIONS.is_dry_run(handler.name()) {
+        let res = handler.dry_run(ctx, args.clone()).await;
+        let outcome = match &res {
+            Ok(outcome) if outcome.success => format!("dry_run: {}", outcome.message),
+            Ok(outcome) => format!("dry_run rejected: {}", outcome.message),
+            Err(err) => format!("dry_run failed: {err}"),
+        };
+        log::info!(
+            "dry run of {} with {} in convo {}: {outcome}",
+            handler.name(),
+            args,
+            ctx.convo_id
+        );
+        let rows_affected = res
+            .as_ref()
+            .ok()
+            .map(|outcome| outcome.rows_affected as i64);
+        audit(ctx, handler.name(), &args, &outcome, rows_affected).await?;
+        return Ok(ActionOutcome::failure(DRY_RUN_REPLY.to_owned()));
+    }
+    let res = handler.execute(ctx, args.clone()).await;
+    match &res {
+        Ok(outcome) if outcome.success => {
+            let audit_id = audit(
+                ctx,
+                handler.name(),
+                &args,
+                "success",
+                Some(outcome.rows_affected as i64),
+            )
+            .await?;
+            if let Some(undo) = &outcome.undo {
+                DB.set_undo_data(audit_id, undo).await?;
+            }
+        }
+        Ok(outcome) => {
+            audit(
+                ctx,
+                handler.name(),
+                &args,
+                &format!("rejected: {}", outcome.message),
+                Some(outcome.rows_affected as i64),
+            )
+            .await?;
+        }
+        Err(err) => {
+            audit(ctx, handler.name(), &args, &format!("failed: {err}"), None).await?;
+        }
+    }
+    res
+}
+
+/// Reverts the action with the given audit log entry, if it can be undone and has not been yet, and
+/// records the reversal in the audit log. Returns what happened, for the admin.
+pub async fn undo_action(audit_id: i64, admin: &str) -> anyhow::Result<String> {
+    let Some((entry, undo)) = DB.take_undo_data(audit_id).await? else {
+        return Ok(format!(
+            "audit entry #{audit_id} cannot be undone, or was already undone"
+        ));
+    };
+    let Some(handler) = ACTIONS.get(&entry.action) else {
+        DB.set_undo_data(audit_id, &undo).await?;
+        anyhow::bail!("action {} is no longer enabled", entry.action)
+    };
+    let res = handler.undo(undo.clone()).await;
+    let (outcome, reply) = match &res {
+        Ok(outcome) if outcome.success => (
+            format!("undo of #{audit_id} by {admin}: {}", outcome.message),
+            format!("undid #{audit_id}: {}", outcome.message),
+        ),
+        Ok(outcome) => (
+            format!(
+                "undo of #{audit_id} by {admin} rejected: {}",
+                outcome.message
+            ),
+            format!("cannot undo #{audit_id}: {}", outcome.message),
+        ),
+        Err(err) => (
+            format!("undo of #{audit_id} by {admin} failed: {err}"),
+            format!("undoing #{audit_id} failed: {err}"),
+        ),
+    };
+    // nothing changed, so the admin can try again
+    if !res.as_ref().is_ok_and(|outcome| outcome.success) {
+        DB.set_undo_data(audit_id, &undo).await?;
+    }
+    DB.insert_audit_entry(&AuditEntry {
+        action: entry.action,
+        args: entry.args,
+        convo_id: entry.convo_id,
+        platform: entry.platform,
+        requester_id: entry.requester_id,
+        requester: entry.requester,
+        outcome,
+        rows_affected: res.ok().map(|outcome| outcome.rows_affected as i64),
+        ..Default::default()
+    })
+    .await?;
+    log::info!("{reply}");
+    Ok(reply)
+}
+
+/// Adds an entry about an action in this context to the audit log
+async fn audit(
+    ctx: &ActionContext,
+    action: &str,
+    args: &Value,
+    outcome: &str,
+    rows_affected: Option<i64>,
+) -> anyhow::Result<i64> {
+    DB.insert_audit_entry(&AuditEntry {
+        action: action.to_owned(),
+        args: args.clone(),
+        convo_id: Some(ctx.convo_id),
+        platform: Some(ctx.requester.platform.to_string()),
+        requester_id: ctx.requester.id.clone(),
+        requester: ctx.requester.name.clone(),
+        model: ctx.model.clone(),
+        outcome: outcome.to_owned(),
+        rows_affected,
+        ..Default::default()
+    })
+    .await
+}
+
+/// The prefix the handlers put before what a requester wrote: the email's subject, or the
+/// Telegram username of users who have one
+fn message_prefix(requester: &Requester) -> String {
+    match &requester.reply_to {
+        ReplyTarget::Email { subject, .. } => format!("{subject}: "),
+        ReplyTarget::Telegram { .. } => requester
+            .name
+            .strip_prefix('@')
+            .map(|username| format!("{username}: "))
+            .unwrap_or_default(),
+    }
+}
+
+/// Whether a user's message confirms a pending action: the first line they wrote after `prefix`,
+/// ignoring any quoted email, is a yes, followed by nothing but polite words
+fn is_confirmation(text: &str, prefix: &str) -> bool {
+    let text = text.strip_prefix(prefix).unwrap_or(text);
+    let Some(first_line) = text
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with('>'))
+    else {
+        return false;
+    };
+    let mut words = first_line
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase());
+    words
+        .next()
+        .is_some_and(|word| CONFIRMATIONS.contains(&word.as_str()))
+        && words.all(|word| {
+            CONFIRMATIONS.contains(&word.as_str()) || POLITE_WORDS.contains(&word.as_str())
+        })
+}
+
+/// All the actions the bot can perform, minus those disabled in `ActionsConfig`
+pub struct ActionRegistry {
+    handlers: Vec<Box<dyn ActionHandler>>,
+}
+
+impl ActionRegistry {
+    fn from_config() -> Self {
+        let all: Vec<Box<dyn ActionHandler>> = vec![
+            Box::new(TransferPlus),
+            Box::new(Escalate),
+            Box::new(AccountLookup),
+        ];
+        let settings = &CONFIG.actions_config.as_ref().unwrap().actions;
+        Self {
+            handlers: all
+                .into_iter()
+                .filter(|handler| {
+                    settings
+                        .get(handler.name())
+                        .is_none_or(|settings| settings.enabled)
+                })
+                .collect(),
+        }
+    }
+
+    /// Looks up an enabled action by name
+    pub fn get(&self, name: &str) -> Option<&dyn ActionHandler> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.name() == name)
+            .map(|handler| handler.as_ref())
+    }
+
+    /// Whether an action is configured to only pretend to run
+    pub fn is_dry_run(&self, name: &str) -> bool {
+        self.settings(name).is_some_and(|settings| settings.dry_run)
+    }
+
+    /// Whether an action is configured to wait for the admin's approval
+    pub fn requires_approval(&self, name: &str) -> bool {
+        self.settings(name)
+            .is_some_and(|settings| settings.requires_approval)
+    }
+
+    fn settings(&self, name: &str) -> Option<&ActionSettings> {
+        CONFIG.actions_config.as_ref().unwrap().actions.get(name)
+    }
+
+    /// Names of the enabled actions
+    pub fn names(&self) -> Vec<&'static str> {
+        self.handlers.iter().map(|handler| handler.name()).collect()
+    }
+
+    /// The part of the system prompt that teaches the model the response format and actions
+    pub fn prompt(&self) -> String {
+        let mut prompt = format!(
+            r#"You *always* respond with a json struct of three fields: "action", "args" and "text". Some examples:
+- {{"action": "{NO_ACTION}", "args": {{}}, "text": "Good morning! How can I help you with Geph today? I know how to say things like\n - \"Hello\"\n - \"Goodbye\"\nand many other things."}}
+- {{"action": "{ABORT}", "args": {{}}, "text": ""}}
+When you pick an action other than "{NO_ACTION}" or "{ABORT}", the user does not see your "text". Instead, you are given the action's result as a system message starting with "Action result:", and then you either reply to the user with "{NO_ACTION}" or perform another action.
+These are the available actions and when/how you should use each one:
+1. "{NO_ACTION}": this means do no action. Use this when you're regularly talking to the user
+2. "{ABORT}": this means do not reply. Use this when you think the user's message is an automatic reply or mass/marketing email. When you use this action, do not put anything in the "text" field.
+"#
+        );
+        for (i, handler) in self.handlers.iter().enumerate() {
+            prompt += &format!(
+                "{}. \"{}\": {} Its \"args\" must match this JSON schema: {}\n",
+                i + 3,
+                handler.name(),
+                handler.description(),
+                handler.args_schema()
+            );
+        }
+        prompt += r#"
+Be very, very careful to ALWAYS respond in the given json format, with one of the actions above! Don't format the json twice! Don't put the response into a markdown code block! For example, this is VERY WRONG:
+
+```json
+{"action": "none", "args": {}, "text": "Hi! I just love Geph."}
+```
+
+This is correct:
+{"action": "none", "args": {}, "text": "Hi! I just love Geph."}
+"#;
+        prompt
+    }
+}
+
+/// The enabled actions, set up at startup
+pub static ACTIONS: Lazy<ActionRegistry> = Lazy::new(ActionRegistry::from_config);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirms_telegram_replies() {
+        assert!(is_confirmation("someuser: yes", "someuser: "));
+        assert!(is_confirmation("someuser: Yes, please!", "someuser: "));
+        assert!(is_confirmation("someuser: 是的", "someuser: "));
+        assert!(!is_confirmation("someuser: no", "someuser: "));
+        assert!(!is_confirmation("someuser: ", "someuser: "));
+    }
+
+    #[test]
+    fn confirms_email_replies_with_quotes() {
+        let text = "RE: Plus transfer: yes go ahead\n\n> ------- Original Message -------\n> Transfer Plus from A to B? Reply YES within 10 minutes to confirm.";
+        assert!(is_confirmation(text, "RE: Plus transfer: "));
+        let text = "RE: Plus transfer: \n> Transfer Plus from A to B? Reply YES to confirm.";
+        assert!(!is_confirmation(text, "RE: Plus transfer: "));
+    }
+
+    #[test]
+    fn reads_users_without_username_as_written() {
+        // without a username, the handler adds no prefix, so "Note" is the user's first word
+        assert!(!is_confirmation("Note: yes", ""));
+        assert!(is_confirmation("yes", ""));
+    }
+
+    #[test]
+    fn rejects_hedged_answers() {
+        assert!(!is_confirmation("someuser: yes but wait", "someuser: "));
+        assert!(!is_confirmation(
+            "someuser: yes, to the other account",
+            "someuser: "
+        ));
+        assert!(!is_confirmation("someuser: yesterday", "someuser: "));
+    }
+}
@@ -0,0 +1,60 @@
+use crate::DB;
+
+use super::{ActionContext, ACTIONS};
+
+/// A limit that kept an action from being performed
+pub(super) enum RateLimit {
+    /// the requester asked for the action too recently, and can ask again later
+    Cooldown(String),
+    /// the requester kept asking for the action during its cooldown, which calls for a human to look
+    RepeatedCooldown(String),
+    /// all users together asked for the action too often, which calls for a human to look
+    Global(String),
+}
+
+/// Checks the action's limits for this requester and counts the attempt if they allow it.
+/// Returns the limit that was hit otherwise.
+///
+/// Requesters are counted by the blind index of their id when encryption is enabled, so cooldowns
+/// start over after `rotate-key`.
+pub(super) async fn check_rate_limit(
+    ctx: &ActionContext,
+    action: &str,
+) -> anyhow::Result<Option<RateLimit>> {
+    if let Some(settings) = ACTIONS.settings(action) {
+        if let Some(cooldown_secs) = settings.cooldown_secs {
+            let requester_id = &ctx.requester.id;
+            let recent = DB
+                .count_action_attempts(action, Some(requester_id), cooldown_secs, false)
+                .await?;
+            if recent > 0 {
+                let turned_away = DB
+                    .count_action_attempts(action, Some(requester_id), cooldown_secs, true)
+                    .await?;
+                DB.insert_action_attempt(action, requester_id, true).await?;
+                let reason = format!(
+                    "{action} can only be asked for once every {} minutes",
+                    cooldown_secs / 60
+                );
+                return Ok(Some(if turned_away > 0 {
+                    RateLimit::RepeatedCooldown(format!(
+                        "{reason}, and the user already asked again before"
+                    ))
+                } else {
+                    RateLimit::Cooldown(reason)
+                }));
+            }
+        }
+        if let Some(max_per_hour) = settings.max_per_hour {
+            let recent = DB.count_action_attempts(action, None, 3600, false).await?;
+            if recent >= max_per_hour {
+                return Ok(Some(RateLimit::Global(format!(
+                    "{action} was already asked for {recent} times in the last hour, by all users"
+                ))));
+            }
+        }
+    }
+    DB.insert_action_attempt(action, &ctx.requester.id, false)
+        .await?;
+    Ok(None)
+}
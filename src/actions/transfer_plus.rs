@@ -0,0 +1,278 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{Connection, PgConnection, Row};
+
+use super::{binder, ActionContext, ActionHandler, ActionOutcome};
+
+/// Moves a user's Plus subscription from an account they lost access to, to a new one
+pub struct TransferPlus;
+
+#[derive(Deserialize)]
+struct TransferPlusArgs {
+    old_uname: String,
+    new_uname: String,
+}
+
+/// Who owned the Plus before a transfer, to move it back
+#[derive(Serialize, Deserialize)]
+struct TransferUndo {
+    old_id: i64,
+    new_id: i64,
+    recurring_subs: u64,
+}
+
+#[async_trait]
+impl ActionHandler for TransferPlus {
+    fn name(&self) -> &'static str {
+        "transfer_plus"
+    }
+
+    fn description(&self) -> &'static str {
+        "transfer Plus time from one account to another. Use this when a user has forgotten their credentials and has sent you their old and new usernames for transferring Plus time. Be sure to format the json correctly! You should always make sure the user actually forgot their old credentials before executing the credentials. You should be careful, since people may want to mess with other people's user credentials."
+    }
+
+    fn args_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "old_uname": {"type": "string", "description": "username of the account to take Plus from"},
+                "new_uname": {"type": "string", "description": "username of the account to give Plus to"}
+            },
+            "required": ["old_uname", "new_uname"]
+        })
+    }
+
+    async fn execute(&self, _ctx: &ActionContext, args: Value) -> anyhow::Result<ActionOutcome> {
+        let TransferPlusArgs {
+            old_uname,
+            new_uname,
+        } = serde_json::from_value(args)?;
+        let result = transfer_plus(&old_uname, &new_uname, false).await?;
+        Ok(describe(result, &old_uname, &new_uname, false))
+    }
+
+    async fn dry_run(&self, _ctx: &ActionContext, args: Value) -> anyhow::Result<ActionOutcome> {
+        let TransferPlusArgs {
+            old_uname,
+            new_uname,
+        } = serde_json::from_value(args)?;
+        let result = transfer_plus(&old_uname, &new_uname, true).await?;
+        Ok(describe(result, &old_uname, &new_uname, true))
+    }
+
+    async fn undo(&self, undo: Value) -> anyhow::Result<ActionOutcome> {
+        untransfer_plus(serde_json::from_value(undo)?).await
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn summary(&self, args: &Value) -> String {
+        match serde_json::from_value::<TransferPlusArgs>(args.clone()) {
+            Ok(args) => format!(
+                "Transfer Plus from {} to {}?",
+                args.old_uname, args.new_uname
+            ),
+            Err(_) => format!("Transfer Plus with {args}?"),
+        }
+    }
+}
+
+/// Puts the result of a (dry-run) transfer into words
+fn describe(
+    result: TransferResult,
+    old_uname: &str,
+    new_uname: &str,
+    dry_run: bool,
+) -> ActionOutcome {
+    match result {
+        TransferResult::Transferred {
+            subscriptions,
+            recurring_subs,
+            ..
+        } if dry_run => ActionOutcome::success(
+            format!("Plus would be transferred from {old_uname} to {new_uname}, moving {subscriptions} subscription and {recurring_subs} recurring payments."),
+            subscriptions + recurring_subs,
+        ),
+        TransferResult::Transferred {
+            old_id,
+            new_id,
+            subscriptions,
+            recurring_subs,
+        } => ActionOutcome::success(
+            format!("Plus was transferred from {old_uname} to {new_uname}."),
+            subscriptions + recurring_subs,
+        )
+        .with_undo(json!(TransferUndo {
+            old_id,
+            new_id,
+            recurring_subs,
+        })),
+        TransferResult::SameAccount => ActionOutcome::failure(format!(
+            "Nothing was transferred: {old_uname} and {new_uname} are the same account."
+        )),
+        TransferResult::OldAccountNotFound => ActionOutcome::failure(format!(
+            "Nothing was transferred: there is no account with the username {old_uname}."
+        )),
+        TransferResult::NewAccountNotFound => ActionOutcome::failure(format!(
+            "Nothing was transferred: there is no account with the username {new_uname}."
+        )),
+        TransferResult::NoSubscription => ActionOutcome::failure(format!(
+            "Nothing was transferred: {old_uname} does not have Plus."
+        )),
+        TransferResult::NewAccountHasSubscription => ActionOutcome::failure(format!(
+            "Nothing was transferred: {new_uname} already has Plus, so a human needs to merge the two subscriptions."
+        )),
+    }
+}
+
+/// What came of a Plus transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferResult {
+    Transferred {
+        old_id: i64,
+        new_id: i64,
+        subscriptions: u64,
+        recurring_subs: u64,
+    },
+    SameAccount,
+    OldAccountNotFound,
+    NewAccountNotFound,
+    /// the old account has no Plus to transfer
+    NoSubscription,
+    /// the new account already has Plus, which the transfer would clash with
+    NewAccountHasSubscription,
+}
+
+/// Moves the subscription and any recurring payment of `old_uname` to `new_uname`, in a single
+/// transaction, after checking that the transfer makes sense. A dry run only runs the checks, in a
+/// read-only transaction, and counts what would be moved.
+async fn transfer_plus(
+    old_uname: &str,
+    new_uname: &str,
+    dry_run: bool,
+) -> anyhow::Result<TransferResult> {
+    log::debug!("transfer_plus({old_uname}, {new_uname}, dry_run: {dry_run})");
+    let mut conn = binder::connect().await?;
+    let mut tx = conn.begin().await?;
+    if dry_run {
+        sqlx::query("set transaction read only")
+            .execute(&mut tx)
+            .await?;
+    }
+
+    let Some(old_id) = binder::user_id(&mut tx, old_uname).await? else {
+        return Ok(TransferResult::OldAccountNotFound);
+    };
+    let Some(new_id) = binder::user_id(&mut tx, new_uname).await? else {
+        return Ok(TransferResult::NewAccountNotFound);
+    };
+    if old_id == new_id {
+        return Ok(TransferResult::SameAccount);
+    }
+    if !has_subscription(&mut tx, old_id, !dry_run).await? {
+        return Ok(TransferResult::NoSubscription);
+    }
+    if has_subscription(&mut tx, new_id, !dry_run).await? {
+        return Ok(TransferResult::NewAccountHasSubscription);
+    }
+    if dry_run {
+        let recurring_subs: i64 =
+            sqlx::query("select count(*) from recurring_subs where user_id = $1")
+                .bind(old_id)
+                .fetch_one(&mut tx)
+                .await?
+                .get(0);
+        return Ok(TransferResult::Transferred {
+            old_id,
+            new_id,
+            subscriptions: 1,
+            recurring_subs: recurring_subs as u64,
+        });
+    }
+
+    let subscriptions = sqlx::query("update subscriptions set id = $1 where id = $2")
+        .bind(new_id)
+        .bind(old_id)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    let recurring_subs = sqlx::query("update recurring_subs set user_id = $1 where user_id = $2")
+        .bind(new_id)
+        .bind(old_id)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    if subscriptions != 1 {
+        // dropping the transaction rolls it back
+        anyhow::bail!("expected to move 1 subscription, would have moved {subscriptions}");
+    }
+    tx.commit().await?;
+    log::debug!("moved {subscriptions} subscriptions and {recurring_subs} recurring subs");
+    Ok(TransferResult::Transferred {
+        old_id,
+        new_id,
+        subscriptions,
+        recurring_subs,
+    })
+}
+
+/// Moves the Plus of a transfer back to the old account, in a single transaction, unless either
+/// account's Plus changed since
+async fn untransfer_plus(undo: TransferUndo) -> anyhow::Result<ActionOutcome> {
+    let TransferUndo {
+        old_id,
+        new_id,
+        recurring_subs,
+    } = undo;
+    log::debug!("untransfer_plus({old_id}, {new_id})");
+    let mut conn = binder::connect().await?;
+    let mut tx = conn.begin().await?;
+    if !has_subscription(&mut tx, new_id, true).await? {
+        return Ok(ActionOutcome::failure(format!(
+            "account #{new_id} no longer has the Plus it was given"
+        )));
+    }
+    if has_subscription(&mut tx, old_id, true).await? {
+        return Ok(ActionOutcome::failure(format!(
+            "account #{old_id} has Plus again"
+        )));
+    }
+
+    let subscriptions = sqlx::query("update subscriptions set id = $1 where id = $2")
+        .bind(old_id)
+        .bind(new_id)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+    let moved_recurring_subs =
+        sqlx::query("update recurring_subs set user_id = $1 where user_id = $2")
+            .bind(old_id)
+            .bind(new_id)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+    if subscriptions != 1 || moved_recurring_subs != recurring_subs {
+        // dropping the transaction rolls it back
+        anyhow::bail!("expected to move back 1 subscription and {recurring_subs} recurring subs, would have moved {subscriptions} and {moved_recurring_subs}");
+    }
+    tx.commit().await?;
+    Ok(ActionOutcome::success(
+        format!("Plus was moved back from account #{new_id} to account #{old_id}."),
+        subscriptions + moved_recurring_subs,
+    ))
+}
+
+/// Whether a binder user has a Plus subscription, optionally locking it for the rest of the
+/// transaction
+async fn has_subscription(conn: &mut PgConnection, id: i64, lock: bool) -> anyhow::Result<bool> {
+    let query = if lock {
+        "select 1 from subscriptions where id = $1 for update"
+    } else {
+        "select 1 from subscriptions where id = $1"
+    };
+    let row = sqlx::query(query).bind(id).fetch_optional(conn).await?;
+    Ok(row.is_some())
+}
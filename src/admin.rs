@@ -0,0 +1,220 @@
+use anyhow::Context;
+
+use crate::{
+    actions::undo_action,
+    database::{ConvoStatus, RatingGroup},
+    CONFIG, DB,
+};
+
+/// How many search results fit comfortably into one Telegram message
+const ADMIN_SEARCH_LIMIT: u32 = 10;
+
+/// How many conversations `#convos` lists
+const ADMIN_CONVOS_LIMIT: u32 = 30;
+
+/// How many dead jobs `#jobs` lists
+const ADMIN_DEAD_JOBS_LIMIT: u32 = 10;
+
+/// How many audit log entries `#audit` lists
+const ADMIN_AUDIT_LIMIT: u32 = 10;
+
+/// How many days `#ratings` covers, unless told otherwise
+const ADMIN_RATINGS_DAYS: u32 = 14;
+
+/// How many models and facts `#ratings` lists
+const ADMIN_RATINGS_LIMIT: u32 = 10;
+
+/// The commands `admin_command` runs
+const COMMANDS: &[&str] = &[
+    "#search", "#status", "#convos", "#jobs", "#retry", "#undo", "#ratings", "#audit",
+];
+
+/// Whether `text` contains an admin command
+pub fn is_admin_command(text: &str) -> bool {
+    COMMANDS.iter().any(|command| text.contains(command))
+}
+
+/// Runs the admin command contained in `text`, if there is one, returning the reply to the admin.
+/// `#learn` is not handled here, since its reply is part of the conversation.
+pub async fn admin_command(text: &str) -> Option<anyhow::Result<String>> {
+    if let Some((_, query)) = text.split_once("#search") {
+        return Some(search(query.trim()).await);
+    }
+    if let Some((_, args)) = text.split_once("#status") {
+        return Some(status(args.trim()).await);
+    }
+    if let Some((_, status)) = text.split_once("#convos") {
+        return Some(convos(status.trim()).await);
+    }
+    if text.contains("#jobs") {
+        return Some(jobs().await);
+    }
+    if let Some((_, job_id)) = text.split_once("#retry") {
+        return Some(retry(job_id.trim()).await);
+    }
+    if let Some((_, audit_id)) = text.split_once("#undo") {
+        return Some(undo(audit_id.trim()).await);
+    }
+    if let Some((_, days)) = text.split_once("#ratings") {
+        return Some(ratings(days.trim()).await);
+    }
+    if let Some((_, convo_id)) = text.split_once("#audit") {
+        return Some(audit(convo_id.trim()).await);
+    }
+    None
+}
+
+async fn search(query: &str) -> anyhow::Result<String> {
+    if query.is_empty() {
+        return Ok("usage: #search [words to search for]".to_owned());
+    }
+    let hits = DB.search_msgs(query, ADMIN_SEARCH_LIMIT).await?;
+    if hits.is_empty() {
+        return Ok(format!("no messages found for \"{query}\""));
+    }
+    Ok(hits
+        .iter()
+        .map(|hit| hit.to_string())
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// `#status <convo id>` shows a conversation's status, `#status <convo id> <status>` changes it
+async fn status(args: &str) -> anyhow::Result<String> {
+    let mut args = args.split_whitespace();
+    let Some(convo_id) = args.next() else {
+        return Ok(
+            "usage: #status [convo id] [open|waiting_on_user|escalated|resolved]".to_owned(),
+        );
+    };
+    let convo_id: i64 = convo_id.parse().context("convo id must be a number")?;
+    match args.next() {
+        Some(status) => {
+            let status: ConvoStatus = status.parse()?;
+            DB.set_convo_status(convo_id, status).await?;
+            Ok(format!("convo {convo_id} is now {status}"))
+        }
+        None => match DB.get_convo_status(convo_id).await? {
+            Some(status) => Ok(format!("convo {convo_id} is {status}")),
+            None => Ok(format!("there is no convo {convo_id}")),
+        },
+    }
+}
+
+/// `#convos <status>` lists the most recently active conversations with that status
+async fn convos(status: &str) -> anyhow::Result<String> {
+    let status: ConvoStatus = if status.is_empty() {
+        ConvoStatus::Escalated
+    } else {
+        status.parse()?
+    };
+    let convos = DB
+        .get_convos_with_status(status, ADMIN_CONVOS_LIMIT)
+        .await?;
+    if convos.is_empty() {
+        return Ok(format!("no {status} convos"));
+    }
+    Ok(convos
+        .iter()
+        .map(|(convo_id, platform, date)| {
+            format!(
+                "convo {convo_id} | {} | last active {}",
+                platform.as_deref().unwrap_or("unknown platform"),
+                date.as_deref().unwrap_or("unknown")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// `#jobs` shows the state of the job queue and the latest jobs that failed for good
+async fn jobs() -> anyhow::Result<String> {
+    let counts = DB
+        .get_job_counts()
+        .await?
+        .iter()
+        .map(|(status, count)| format!("{status}: {count}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut reply = format!("jobs: {counts}");
+    for (job_id, kind, error) in DB.get_dead_jobs(ADMIN_DEAD_JOBS_LIMIT).await? {
+        reply += &format!("\n\ndead job {job_id} ({kind}): {error}");
+    }
+    Ok(reply)
+}
+
+/// `#retry <job id>` puts a dead job back into the queue
+async fn retry(job_id: &str) -> anyhow::Result<String> {
+    let job_id: i64 = job_id.parse().context("usage: #retry [job id]")?;
+    if DB.retry_dead_job(job_id).await? {
+        Ok(format!("job {job_id} is queued again"))
+    } else {
+        Ok(format!("there is no dead job {job_id}"))
+    }
+}
+
+/// `#undo <audit id>` reverts a reversible action
+async fn undo(audit_id: &str) -> anyhow::Result<String> {
+    let audit_id: i64 = audit_id.parse().context("usage: #undo [audit id]")?;
+    let admin = &CONFIG.telegram_config.as_ref().unwrap().admin_uname;
+    undo_action(audit_id, &format!("@{admin}")).await
+}
+
+/// `#audit` lists the latest action audit log entries, `#audit <convo id>` those of one conversation
+async fn audit(convo_id: &str) -> anyhow::Result<String> {
+    let convo_id: Option<i64> = if convo_id.is_empty() {
+        None
+    } else {
+        Some(convo_id.parse().context("usage: #audit [convo id]")?)
+    };
+    let entries = DB
+        .get_audit_entries(convo_id, None, ADMIN_AUDIT_LIMIT)
+        .await?;
+    if entries.is_empty() {
+        return Ok("no audit entries".to_owned());
+    }
+    Ok(entries
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// `#ratings [days]` reports how users rated the bot's replies, per day, per model and per fact
+async fn ratings(days: &str) -> anyhow::Result<String> {
+    let days: u32 = if days.is_empty() {
+        ADMIN_RATINGS_DAYS
+    } else {
+        days.parse().context("usage: #ratings [days]")?
+    };
+    let mut reply = format!("ratings in the last {days} days");
+    for (title, group, limit) in [
+        ("per day", RatingGroup::Day, days),
+        (
+            "per model, worst first",
+            RatingGroup::Model,
+            ADMIN_RATINGS_LIMIT,
+        ),
+        (
+            "per fact used, worst first",
+            RatingGroup::Fact,
+            ADMIN_RATINGS_LIMIT,
+        ),
+    ] {
+        let counts = DB
+            .get_rating_counts(group, days as i64 * 86400, limit)
+            .await?;
+        // every rating falls on some day, but not every reply was given facts
+        if counts.is_empty() && matches!(group, RatingGroup::Day) {
+            return Ok(format!("no ratings in the last {days} days"));
+        }
+        reply += &format!("\n\n{title}:");
+        if counts.is_empty() {
+            reply += "\nnone";
+        }
+        for count in counts {
+            reply += &format!("\n{count}");
+        }
+    }
+    Ok(reply)
+}
@@ -0,0 +1,164 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use anyhow::Context;
+use argh::FromArgs;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{
+    database::{ConvoFilter, Platform},
+    export::export_jsonl,
+    DB,
+};
+
+/// Offline commands that work on the bot's database instead of running the bot
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum Command {
+    Search(SearchCmd),
+    Export(ExportCmd),
+    RotateKey(RotateKeyCmd),
+    Audit(AuditCmd),
+}
+
+/// Full-text search through past support conversations.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "search")]
+pub struct SearchCmd {
+    /// maximum number of results to show
+    #[argh(option, default = "20")]
+    limit: u32,
+    /// words to search for
+    #[argh(positional, greedy)]
+    query: Vec<String>,
+}
+
+/// Export support conversations as OpenAI fine-tuning JSONL.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "export")]
+pub struct ExportCmd {
+    /// file to write to; defaults to stdout
+    #[argh(option, short = 'o')]
+    out: Option<PathBuf>,
+    /// only export conversations from this platform (telegram or email)
+    #[argh(option)]
+    platform: Option<Platform>,
+    /// only export conversations started on or after this date (YYYY-MM-DD)
+    #[argh(option)]
+    since: Option<String>,
+    /// only export conversations started before this date (YYYY-MM-DD)
+    #[argh(option)]
+    until: Option<String>,
+    /// only export conversations that users rated positively with the Telegram feedback buttons
+    #[argh(switch)]
+    rated_positive: bool,
+    /// skip conversations in which the bot performed an action
+    #[argh(switch)]
+    exclude_actions: bool,
+    /// include the current prompt and learned facts as the system message
+    #[argh(switch)]
+    system_prompt: bool,
+}
+
+/// Re-encrypt the chat history with the current history_key. Run this after enabling
+/// encryption, and after moving the previous key to old_history_keys.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "rotate-key")]
+pub struct RotateKeyCmd {}
+
+/// Show the audit log of actions the bot performed or was asked to perform, newest first.
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "audit")]
+pub struct AuditCmd {
+    /// only show entries from this conversation
+    #[argh(option)]
+    convo_id: Option<i64>,
+    /// only show entries for this action
+    #[argh(option)]
+    action: Option<String>,
+    /// only show entries whose requester or args contain this text, such as a username
+    #[argh(option)]
+    user: Option<String>,
+    /// maximum number of entries to show
+    #[argh(option, default = "50")]
+    limit: u32,
+}
+
+pub async fn run_command(command: &Command) -> anyhow::Result<()> {
+    match command {
+        Command::Search(cmd) => {
+            let hits = DB.search_msgs(&cmd.query.join(" "), cmd.limit).await?;
+            if hits.is_empty() {
+                println!("no results");
+            }
+            for hit in hits {
+                println!("{hit}");
+            }
+        }
+        Command::Export(cmd) => {
+            for date in [&cmd.since, &cmd.until].into_iter().flatten() {
+                check_date(date)?;
+            }
+            let filter = ConvoFilter {
+                platform: cmd.platform,
+                since: cmd.since.clone(),
+                until: cmd.until.clone(),
+                rated_positive: cmd.rated_positive,
+                exclude_actions: cmd.exclude_actions,
+            };
+            let count = match &cmd.out {
+                Some(path) => {
+                    let file = File::create(path)
+                        .with_context(|| format!("cannot create {}", path.display()))?;
+                    export_jsonl(&filter, cmd.system_prompt, &mut BufWriter::new(file)).await?
+                }
+                None => export_jsonl(&filter, cmd.system_prompt, &mut std::io::stdout()).await?,
+            };
+            eprintln!("exported {count} conversations");
+        }
+        Command::Audit(cmd) => {
+            // args and requesters may be encrypted, so they can only be matched after decryption
+            let fetch_limit = if cmd.user.is_some() {
+                u32::MAX
+            } else {
+                cmd.limit
+            };
+            let entries = DB
+                .get_audit_entries(cmd.convo_id, cmd.action.as_deref(), fetch_limit)
+                .await?;
+            let mut shown = 0;
+            for entry in entries {
+                if shown == cmd.limit {
+                    break;
+                }
+                if let Some(user) = &cmd.user {
+                    if !entry.requester.contains(user.as_str())
+                        && !entry.requester_id.contains(user.as_str())
+                        && !entry.args.to_string().contains(user.as_str())
+                    {
+                        continue;
+                    }
+                }
+                println!("{entry}");
+                shown += 1;
+            }
+            if shown == 0 {
+                println!("no audit entries");
+            }
+        }
+        Command::RotateKey(_) => {
+            let count = DB.reencrypt_all().await?;
+            println!("re-encrypted {count} rows");
+        }
+    }
+    Ok(())
+}
+
+/// SQLite silently treats malformed dates as NULL, which would match nothing
+fn check_date(date: &str) -> anyhow::Result<()> {
+    static DATE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap());
+    if !DATE.is_match(date) {
+        anyhow::bail!("invalid date {date}, expected YYYY-MM-DD")
+    }
+    Ok(())
+}
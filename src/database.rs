@@ -1,8 +1,12 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{Connection, Executor, Row, SqliteConnection, SqlitePool};
 
-use crate::Message;
+use crate::{
+    encryption::{is_encrypted, words, HistoryCipher},
+    Message,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Role {
@@ -10,56 +14,253 @@ pub enum Role {
     Assistant,
 }
 
-impl Role {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Role::User => "user".to_owned(),
-            Role::Assistant => "assistant".to_owned(),
+            Role::User => write!(f, "user"),
+            Role::Assistant => write!(f, "assistant"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Platform {
     Telegram,
     Email,
 }
 
-impl Platform {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Platform::Telegram => write!(f, "telegram"),
+            Platform::Email => write!(f, "email"),
+        }
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "telegram" => Ok(Platform::Telegram),
+            "email" => Ok(Platform::Email),
+            _ => anyhow::bail!("unknown platform {s}; expected telegram or email"),
+        }
+    }
+}
+
+/// Where a conversation is in its lifecycle
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConvoStatus {
+    Open,
+    WaitingOnUser,
+    Escalated,
+    Resolved,
+}
+
+impl std::fmt::Display for ConvoStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Platform::Telegram => "telegram".to_owned(),
-            Platform::Email => "email".to_owned(),
+            ConvoStatus::Open => write!(f, "open"),
+            ConvoStatus::WaitingOnUser => write!(f, "waiting_on_user"),
+            ConvoStatus::Escalated => write!(f, "escalated"),
+            ConvoStatus::Resolved => write!(f, "resolved"),
+        }
+    }
+}
+
+impl std::str::FromStr for ConvoStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(ConvoStatus::Open),
+            "waiting_on_user" => Ok(ConvoStatus::WaitingOnUser),
+            "escalated" => Ok(ConvoStatus::Escalated),
+            "resolved" => Ok(ConvoStatus::Resolved),
+            _ => anyhow::bail!(
+                "unknown status {s}; expected open, waiting_on_user, escalated or resolved"
+            ),
         }
     }
 }
 
 pub struct ChatHistoryDb {
     db_pool: SqlitePool,
+    /// encrypts message text and conversation metadata, if configured
+    cipher: Option<HistoryCipher>,
 }
 
 impl ChatHistoryDb {
     /// Creates a new chat history database
-    pub async fn new(db_path: &str) -> anyhow::Result<Self> {
+    pub async fn new(db_path: &str, cipher: Option<HistoryCipher>) -> anyhow::Result<Self> {
         // create tables
         let mut conn = SqliteConnection::connect(&format!("file:{db_path}?mode=rwc")).await?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS conversations (
                 convo_id BIGINT PRIMARY KEY,
                 platform TEXT,
-                metadata BLOB
+                metadata BLOB,
+                metadata_key TEXT,
+                status TEXT NOT NULL DEFAULT 'open',
+                updated_at BIGINT
             )",
         )
         .await?;
+        ensure_column(&mut conn, "conversations", "metadata_key", "TEXT").await?;
+        ensure_column(
+            &mut conn,
+            "conversations",
+            "status",
+            "TEXT NOT NULL DEFAULT 'open'",
+        )
+        .await?;
+        ensure_column(&mut conn, "conversations", "updated_at", "BIGINT").await?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS messages (
             convo_id BIGINT,
             text TEXT,
             sender TEXT,
+            created_at BIGINT,
+            text_key TEXT,
             FOREIGN KEY(convo_id) REFERENCES conversations(convo_id)
         )",
         )
         .await?;
+        ensure_column(&mut conn, "messages", "created_at", "BIGINT").await?;
+        ensure_column(&mut conn, "messages", "text_key", "TEXT").await?;
+        // for the bot's replies: the model that wrote it, and the ids of the facts in its prompt
+        ensure_column(&mut conn, "messages", "model", "TEXT").await?;
+        ensure_column(&mut conn, "messages", "facts_used", "TEXT").await?;
+        // Full-text index over message text, kept in sync by insert_msg. It is contentless, so
+        // that it never holds message text; with encryption enabled it only holds hashed words.
+        let has_fts =
+            sqlx::query("SELECT 1 FROM sqlite_master WHERE type='table' AND name='messages_fts'")
+                .fetch_optional(&mut conn)
+                .await?
+                .is_some();
+        if !has_fts {
+            conn.execute("CREATE VIRTUAL TABLE messages_fts USING fts5(text, content='')")
+                .await?;
+            // index messages stored before search existed
+            rebuild_search_index(&mut conn, cipher.as_ref()).await?;
+        }
+        // ratings of assistant messages by the users they were sent to: 1 if helpful, -1 if
+        // unhelpful, 0 if the user asked for a human instead
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ratings (
+            msg_id BIGINT,
+            convo_id BIGINT,
+            rating INTEGER,
+            created_at BIGINT
+        )",
+        )
+        .await?;
+        // every action the bot was asked to perform, and what came of it
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS action_audit (
+            audit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            action TEXT NOT NULL,
+            args TEXT,
+            convo_id BIGINT,
+            platform TEXT,
+            requester_id TEXT,
+            requester TEXT,
+            model TEXT,
+            created_at BIGINT NOT NULL,
+            outcome TEXT NOT NULL,
+            rows_affected BIGINT,
+            undo_data TEXT
+        )",
+        )
+        .await?;
+        // inbound messages waiting to be responded to
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS jobs (
+            job_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            payload TEXT,
+            status TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            run_after BIGINT NOT NULL,
+            created_at BIGINT NOT NULL,
+            ordering_key TEXT,
+            progress TEXT
+        )",
+        )
+        .await?;
+        // actions waiting for the user who asked for them to confirm them, at most one per user in
+        // each conversation
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS pending_actions (
+            pending_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            convo_id BIGINT NOT NULL,
+            requester_id TEXT NOT NULL,
+            action TEXT NOT NULL,
+            args TEXT NOT NULL,
+            expires_at BIGINT NOT NULL,
+            model TEXT
+        )",
+        )
+        .await?;
+        // when each requester last asked for each action, for rate limiting, and whether they were
+        // turned away by their cooldown
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS action_attempts (
+            action TEXT NOT NULL,
+            requester_key TEXT NOT NULL,
+            created_at BIGINT NOT NULL,
+            limited BOOLEAN NOT NULL DEFAULT FALSE
+        )",
+        )
+        .await?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS action_attempts_by_action ON action_attempts (action, created_at)",
+        )
+        .await?;
+        // the last Telegram update that was fully handled
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS bot_state (
+            key TEXT PRIMARY KEY,
+            value BIGINT NOT NULL
+        )",
+        )
+        .await?;
+        // Telegram messages the bot has handled, and its replies to them: a long reply is split
+        // into several messages, any of which the user can reply to. edit_date is when the user
+        // last edited the message, if the bot answered an edited version, and job_id the job
+        // that reserved the message before replying.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS telegram_replies (
+            chat_id BIGINT NOT NULL,
+            message_id BIGINT NOT NULL,
+            convo_id BIGINT,
+            reply_message_ids TEXT NOT NULL DEFAULT '[]',
+            edit_date BIGINT NOT NULL DEFAULT 0,
+            job_id BIGINT,
+            created_at BIGINT NOT NULL,
+            PRIMARY KEY (chat_id, message_id)
+        )",
+        )
+        .await?;
+        // actions waiting for the admin to approve or reject them
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS approvals (
+            approval_id INTEGER PRIMARY KEY AUTOINCREMENT,
+            convo_id BIGINT NOT NULL,
+            action TEXT NOT NULL,
+            args TEXT NOT NULL,
+            requester TEXT NOT NULL,
+            model TEXT,
+            status TEXT NOT NULL DEFAULT 'pending',
+            decided_by TEXT,
+            created_at BIGINT NOT NULL,
+            decided_at BIGINT
+        )",
+        )
+        .await?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS facts (
             fact TEXT
@@ -69,6 +270,7 @@ impl ChatHistoryDb {
 
         Ok(Self {
             db_pool: SqlitePool::connect(db_path).await?,
+            cipher,
         })
     }
 
@@ -80,47 +282,182 @@ impl ChatHistoryDb {
         Ok(())
     }
 
-    pub async fn get_all_facts(&self) -> anyhow::Result<Vec<String>> {
-        let facts = sqlx::query("SELECT * FROM facts")
+    /// Gets every learned fact, with its id, in the order they were learned
+    pub async fn get_all_facts(&self) -> anyhow::Result<Vec<(i64, String)>> {
+        let facts = sqlx::query("SELECT rowid, fact FROM facts ORDER BY rowid")
             .fetch_all(&self.db_pool)
             .await?;
-        let ret: Vec<String> = facts.iter().map(|row| row.get("fact")).collect();
+        let ret = facts
+            .iter()
+            .map(|row| (row.get("rowid"), row.get("fact")))
+            .collect();
         Ok(ret)
     }
 
-    pub async fn insert_msg(
+    /// Stores a reply and the message it answers, and saves it as the progress of the job sending it
+    pub async fn store_reply(
+        &self,
+        job_id: i64,
+        question: Option<&Message>,
+        reply: &Message,
+        platform: Platform,
+        metadata: Value,
+        origin: Option<ReplyOrigin<'_>>,
+    ) -> anyhow::Result<ReplyProgress> {
+        // all at once, so that a retry sends the saved reply without storing anything twice
+        let mut tx = self.db_pool.begin().await?;
+        if let Some(question) = question {
+            self.insert_msg(&mut tx, question, platform, Role::User, metadata.clone())
+                .await?;
+        }
+        let msg_id = self
+            .insert_msg(&mut tx, reply, platform, Role::Assistant, metadata)
+            .await?;
+        if let Some(origin) = &origin {
+            set_reply_origin(&mut tx, msg_id, origin).await?;
+        }
+        let progress = ReplyProgress {
+            text: reply.text.clone(),
+            // the model's answers can be rated, what the bot learned cannot
+            rated_msg_id: origin.map(|_| msg_id),
+            in_convo: true,
+            ..Default::default()
+        };
+        self.save_job_progress_in(&mut tx, job_id, &progress)
+            .await?;
+        tx.commit().await?;
+        Ok(progress)
+    }
+
+    /// Adds a message to its conversation, returning the message's id
+    async fn insert_msg(
         &self,
+        conn: &mut SqliteConnection,
         msg: &Message,
         platform: Platform,
         role: Role,
         metadata: Value,
-    ) -> anyhow::Result<()> {
-        let mut tx = self.db_pool.begin().await?;
+    ) -> anyhow::Result<i64> {
+        let metadata = metadata.to_string();
+        // the conversation's status is left alone; only the responder and admins change it
         sqlx::query(
-            "INSERT OR REPLACE INTO conversations (convo_id, platform, metadata) VALUES (?, ?, ?)",
+            "INSERT INTO conversations (convo_id, platform, metadata, metadata_key, updated_at) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(convo_id) DO UPDATE SET platform = ?2, metadata = ?3, metadata_key = ?4, updated_at = ?5",
         )
         .bind(msg.convo_id)
         .bind(platform.to_string())
-        .bind(serde_json::to_vec(&metadata)?)
-        .execute(&mut tx)
+        .bind(self.seal(&metadata)?)
+        .bind(self.lookup_key(&metadata))
+        .bind(unix_now())
+        .execute(&mut *conn)
         .await?;
-        sqlx::query("INSERT INTO messages (convo_id, text, sender) VALUES (?, ?, ?)")
-            .bind(msg.convo_id)
-            .bind(msg.text.clone())
-            .bind(role.to_string())
+        let msg_id = sqlx::query(
+            "INSERT INTO messages (convo_id, text, sender, created_at, text_key) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(msg.convo_id)
+        .bind(self.seal(&msg.text)?)
+        .bind(role.to_string())
+        .bind(unix_now())
+        .bind(self.lookup_key(&msg.text))
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+        sqlx::query("INSERT INTO messages_fts (rowid, text) VALUES (?, ?)")
+            .bind(msg_id)
+            .bind(index_text(self.cipher.as_ref(), &msg.text))
+            .execute(&mut *conn)
+            .await?;
+        Ok(msg_id)
+    }
+
+    /// Records a user's rating of a reply of the bot's, replacing any earlier rating of it.
+    /// Returns the reply's convo id, or None if there is no such reply.
+    pub async fn rate_msg(&self, msg_id: i64, rating: i64) -> anyhow::Result<Option<i64>> {
+        let mut tx = self.db_pool.begin().await?;
+        let Some(row) = sqlx::query("SELECT convo_id FROM messages WHERE rowid = ? AND sender = ?")
+            .bind(msg_id)
+            .bind(Role::Assistant.to_string())
+            .fetch_optional(&mut tx)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let convo_id: i64 = row.get("convo_id");
+        sqlx::query("DELETE FROM ratings WHERE msg_id = ?")
+            .bind(msg_id)
             .execute(&mut tx)
             .await?;
+        sqlx::query(
+            "INSERT INTO ratings (msg_id, convo_id, rating, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(msg_id)
+        .bind(convo_id)
+        .bind(rating)
+        .bind(unix_now())
+        .execute(&mut tx)
+        .await?;
         tx.commit().await?;
+        Ok(Some(convo_id))
+    }
 
-        Ok(())
+    /// Counts the ratings given in the last `max_age_secs` seconds, grouped by day, model or fact,
+    /// with the worst rated groups first when grouping by model or fact
+    pub async fn get_rating_counts(
+        &self,
+        group: RatingGroup,
+        max_age_secs: i64,
+        limit: u32,
+    ) -> anyhow::Result<Vec<RatingCounts>> {
+        let (label, joins, order) = match group {
+            RatingGroup::Day => ("date(ratings.created_at, 'unixepoch')", "", "label DESC"),
+            RatingGroup::Model => (
+                "COALESCE(messages.model, 'unknown model')",
+                "JOIN messages ON messages.rowid = ratings.msg_id",
+                "unhelpful DESC, COUNT(*) DESC",
+            ),
+            RatingGroup::Fact => (
+                "facts.fact",
+                "JOIN messages ON messages.rowid = ratings.msg_id
+                JOIN json_each(messages.facts_used) AS used
+                JOIN facts ON facts.rowid = used.value",
+                "unhelpful DESC, COUNT(*) DESC",
+            ),
+        };
+        let rows = sqlx::query(&format!(
+            "SELECT {label} AS label, SUM(rating > 0) AS helpful, SUM(rating < 0) AS unhelpful,
+                SUM(rating = 0) AS human
+            FROM ratings {joins}
+            WHERE ratings.created_at >= ?
+            GROUP BY {label}
+            ORDER BY {order}
+            LIMIT ?"
+        ))
+        .bind(unix_now() - max_age_secs)
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| RatingCounts {
+                label: row.get("label"),
+                helpful: row.get("helpful"),
+                unhelpful: row.get("unhelpful"),
+                human: row.get("human"),
+            })
+            .collect())
     }
 
     /// Returns the convo id of a message if it exists in the database
     pub async fn txt_to_id(&self, text: &str) -> Option<i64> {
-        match sqlx::query("SELECT convo_id FROM messages WHERE text=?")
-            .bind(text)
-            .fetch_one(&self.db_pool)
-            .await
+        // encrypted messages are found by their blind index, older ones by their text
+        match sqlx::query(
+            "SELECT convo_id FROM messages
+            WHERE text_key IN (SELECT value FROM json_each(?)) OR text=?",
+        )
+        .bind(self.lookup_keys(text))
+        .bind(text)
+        .fetch_one(&self.db_pool)
+        .await
         {
             Ok(row) => {
                 let id: i64 = row.get("convo_id");
@@ -132,10 +469,15 @@ impl ChatHistoryDb {
 
     /// Returns the convo id of an email thread, if it exists in the database
     pub async fn email_metadata_to_id(&self, email_meta: Value) -> Option<i64> {
-        if let Ok(row) = sqlx::query("SELECT convo_id FROM conversations WHERE metadata=?")
-            .bind(email_meta)
-            .fetch_one(&self.db_pool)
-            .await
+        let email_meta = email_meta.to_string();
+        if let Ok(row) = sqlx::query(
+            "SELECT convo_id FROM conversations
+            WHERE metadata_key IN (SELECT value FROM json_each(?)) OR metadata=?",
+        )
+        .bind(self.lookup_keys(&email_meta))
+        .bind(&email_meta)
+        .fetch_one(&self.db_pool)
+        .await
         {
             let id: i64 = row.get("convo_id");
             Some(id)
@@ -144,19 +486,1170 @@ impl ChatHistoryDb {
         }
     }
 
-    /// Returns all messages in DB with the given convo_id with sender info, as (sender, message)
-    /// TODO: order of the messages
+    /// Returns all messages in DB with the given convo_id with sender info, as (sender, message),
+    /// oldest first
     pub async fn get_convo_history(&self, convo_id: i64) -> anyhow::Result<Vec<(String, String)>> {
-        let rows = sqlx::query("SELECT sender, text FROM messages WHERE convo_id=?")
+        let rows = sqlx::query("SELECT sender, text FROM messages WHERE convo_id=? ORDER BY rowid")
             .bind(convo_id)
             .fetch_all(&self.db_pool)
             .await?;
 
+        rows.iter()
+            .map(|row| Ok((row.get("sender"), self.open(row.get("text"))?)))
+            .collect()
+    }
+
+    /// Returns the status of a conversation, or None if it has not been stored yet
+    pub async fn get_convo_status(&self, convo_id: i64) -> anyhow::Result<Option<ConvoStatus>> {
+        let row = sqlx::query("SELECT status FROM conversations WHERE convo_id = ?")
+            .bind(convo_id)
+            .fetch_optional(&self.db_pool)
+            .await?;
+        row.map(|row| row.get::<String, _>("status").parse())
+            .transpose()
+    }
+
+    /// Stores a conversation before its first message is, so that its status can be set while the
+    /// bot responds to that message
+    pub async fn ensure_convo(&self, convo_id: i64, platform: Platform) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO conversations (convo_id, platform, updated_at) VALUES (?, ?, ?)
+            ON CONFLICT(convo_id) DO NOTHING",
+        )
+        .bind(convo_id)
+        .bind(platform.to_string())
+        .bind(unix_now())
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Sets the status of a stored conversation
+    pub async fn set_convo_status(&self, convo_id: i64, status: ConvoStatus) -> anyhow::Result<()> {
+        let updated =
+            sqlx::query("UPDATE conversations SET status = ?, updated_at = ? WHERE convo_id = ?")
+                .bind(status.to_string())
+                .bind(unix_now())
+                .bind(convo_id)
+                .execute(&self.db_pool)
+                .await?
+                .rows_affected();
+        if updated == 0 {
+            anyhow::bail!("no conversation with id {convo_id}")
+        }
+        Ok(())
+    }
+
+    /// Returns the most recently active conversations with the given status,
+    /// as (convo_id, platform, last activity date)
+    pub async fn get_convos_with_status(
+        &self,
+        status: ConvoStatus,
+        limit: u32,
+    ) -> anyhow::Result<Vec<(i64, Option<String>, Option<String>)>> {
+        let rows = sqlx::query(
+            "SELECT convo_id, platform, datetime(updated_at, 'unixepoch') AS date
+            FROM conversations WHERE status = ? ORDER BY updated_at DESC LIMIT ?",
+        )
+        .bind(status.to_string())
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("convo_id"), row.get("platform"), row.get("date")))
+            .collect())
+    }
+
+    /// Resolves open and waiting conversations with no activity in the given window.
+    /// Escalated conversations are left for a human. Returns how many were resolved.
+    pub async fn resolve_inactive_convos(&self, inactive_secs: i64) -> anyhow::Result<u64> {
+        let res = sqlx::query(
+            "UPDATE conversations SET status = ?
+            WHERE status IN (?, ?) AND COALESCE(updated_at, 0) < ?",
+        )
+        .bind(ConvoStatus::Resolved.to_string())
+        .bind(ConvoStatus::Open.to_string())
+        .bind(ConvoStatus::WaitingOnUser.to_string())
+        .bind(unix_now() - inactive_secs)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Adds a job to the queue, returning its id
+    pub async fn insert_job(
+        &self,
+        kind: &str,
+        payload: &str,
+        ordering_key: Option<&str>,
+    ) -> anyhow::Result<i64> {
+        let now = unix_now();
+        let job_id = sqlx::query(
+            "INSERT INTO jobs (kind, payload, ordering_key, status, run_after, created_at) VALUES (?, ?, ?, 'pending', ?, ?)",
+        )
+        .bind(kind)
+        .bind(self.seal(payload)?)
+        .bind(ordering_key.map(|key| self.opaque_key(key)))
+        .bind(now)
+        .bind(now)
+        .execute(&self.db_pool)
+        .await?
+        .last_insert_rowid();
+        Ok(job_id)
+    }
+
+    /// Takes the oldest pending job that is due and marks it as running. Jobs with the same
+    /// ordering key are taken one at a time and in order, so a job waits while an earlier one with
+    /// its key is running or waiting for a retry.
+    pub async fn claim_job(&self) -> anyhow::Result<Option<Job>> {
+        let row = sqlx::query(
+            "UPDATE jobs SET status = 'running', attempts = attempts + 1
+            WHERE job_id = (
+                SELECT job_id FROM jobs AS job WHERE status = 'pending' AND run_after <= ?
+                AND (ordering_key IS NULL OR NOT EXISTS (
+                    SELECT 1 FROM jobs AS earlier
+                    WHERE earlier.ordering_key = job.ordering_key AND earlier.job_id < job.job_id
+                    AND earlier.status IN ('pending', 'running')
+                ))
+                ORDER BY job_id LIMIT 1
+            )
+            RETURNING job_id, kind, payload, attempts, progress",
+        )
+        .bind(unix_now())
+        .fetch_optional(&self.db_pool)
+        .await?;
+        row.map(|row| {
+            Ok(Job {
+                job_id: row.get("job_id"),
+                kind: row.get("kind"),
+                payload: self.open(row.get("payload"))?,
+                attempts: row.get("attempts"),
+                progress: row
+                    .get::<Option<String>, _>("progress")
+                    .map(|progress| anyhow::Ok(serde_json::from_str(&self.open(progress)?)?))
+                    .transpose()?,
+            })
+        })
+        .transpose()
+    }
+
+    /// Saves how far a job got in sending its reply. A retry of the job sends the rest of it.
+    pub async fn save_job_progress(
+        &self,
+        job_id: i64,
+        progress: &ReplyProgress,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.db_pool.acquire().await?;
+        self.save_job_progress_in(&mut conn, job_id, progress).await
+    }
+
+    async fn save_job_progress_in(
+        &self,
+        conn: &mut SqliteConnection,
+        job_id: i64,
+        progress: &ReplyProgress,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE jobs SET progress = ? WHERE job_id = ?")
+            .bind(self.seal(&serde_json::to_string(progress)?)?)
+            .bind(job_id)
+            .execute(conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Marks a job as done, dropping its payload and progress
+    pub async fn finish_job(&self, job_id: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE jobs SET status = 'done', payload = NULL, progress = NULL WHERE job_id = ?",
+        )
+        .bind(job_id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Records a failed attempt at a job. It is retried after `retry_after` seconds,
+    /// or never if that is None, which leaves the job dead.
+    pub async fn fail_job(
+        &self,
+        job_id: i64,
+        error: &str,
+        retry_after: Option<i64>,
+    ) -> anyhow::Result<()> {
+        let (status, run_after) = match retry_after {
+            Some(secs) => ("pending", unix_now() + secs),
+            None => ("dead", unix_now()),
+        };
+        sqlx::query("UPDATE jobs SET status = ?, last_error = ?, run_after = ? WHERE job_id = ?")
+            .bind(status)
+            .bind(error)
+            .bind(run_after)
+            .bind(job_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Puts jobs that were running when the bot stopped back into the queue
+    pub async fn requeue_running_jobs(&self) -> anyhow::Result<u64> {
+        let res = sqlx::query("UPDATE jobs SET status = 'pending' WHERE status = 'running'")
+            .execute(&self.db_pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    /// Puts a dead job back into the queue. Returns false if there is no such dead job.
+    pub async fn retry_dead_job(&self, job_id: i64) -> anyhow::Result<bool> {
+        let res = sqlx::query(
+            "UPDATE jobs SET status = 'pending', attempts = 0, run_after = ? WHERE job_id = ? AND status = 'dead'",
+        )
+        .bind(unix_now())
+        .bind(job_id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Returns the number of jobs in each status
+    pub async fn get_job_counts(&self) -> anyhow::Result<Vec<(String, i64)>> {
+        let rows = sqlx::query("SELECT status, COUNT(*) AS count FROM jobs GROUP BY status")
+            .fetch_all(&self.db_pool)
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("status"), row.get("count")))
+            .collect())
+    }
+
+    /// Returns the most recent dead jobs as (job_id, kind, last error)
+    pub async fn get_dead_jobs(&self, limit: u32) -> anyhow::Result<Vec<(i64, String, String)>> {
+        let rows = sqlx::query(
+            "SELECT job_id, kind, last_error FROM jobs WHERE status = 'dead' ORDER BY job_id DESC LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                (
+                    row.get("job_id"),
+                    row.get("kind"),
+                    row.get::<Option<String>, _>("last_error")
+                        .unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    /// Returns the id of the last Telegram update that was fully handled, or 0
+    pub async fn get_telegram_offset(&self) -> anyhow::Result<i64> {
+        let offset = sqlx::query("SELECT value FROM bot_state WHERE key = 'telegram_offset'")
+            .fetch_optional(&self.db_pool)
+            .await?
+            .map(|row| row.get(0))
+            .unwrap_or(0);
+        Ok(offset)
+    }
+
+    /// Notes that a Telegram update was fully handled. Updates can be handled out of order, so the
+    /// offset only ever moves forward.
+    pub async fn advance_telegram_offset(&self, update_id: i64) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO bot_state (key, value) VALUES ('telegram_offset', ?)
+            ON CONFLICT (key) DO UPDATE SET value = MAX(value, excluded.value)",
+        )
+        .bind(update_id)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns how the bot handled a Telegram message, or None if it has not handled it yet
+    pub async fn get_telegram_reply(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+    ) -> anyhow::Result<Option<TelegramReply>> {
+        let row = sqlx::query(
+            "SELECT convo_id, reply_message_ids, edit_date, job_id FROM telegram_replies
+            WHERE chat_id = ? AND message_id = ?",
+        )
+        .bind(chat_id)
+        .bind(message_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(TelegramReply {
+            convo_id: row.get("convo_id"),
+            reply_message_ids: serde_json::from_str(row.get("reply_message_ids"))?,
+            edit_date: row.get("edit_date"),
+            job_id: row.get("job_id"),
+        }))
+    }
+
+    /// Reserves a Telegram message for the job about to reply to it, keeping the replies to its
+    /// earlier version until the new ones are sent. Returns false if another job already has it.
+    pub async fn reserve_telegram_msg(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        edit_date: i64,
+        convo_id: Option<i64>,
+        job_id: i64,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO telegram_replies (chat_id, message_id, edit_date, convo_id, job_id, created_at) VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (chat_id, message_id) DO UPDATE SET
+                edit_date = excluded.edit_date, convo_id = excluded.convo_id, job_id = excluded.job_id
+            WHERE telegram_replies.edit_date < excluded.edit_date
+                OR telegram_replies.job_id = excluded.job_id",
+        )
+        .bind(chat_id)
+        .bind(message_id)
+        .bind(edit_date)
+        .bind(convo_id)
+        .bind(job_id)
+        .bind(unix_now())
+        .execute(&self.db_pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Notes that the bot has handled a Telegram message, as of its edit at `edit_date` (0 if it
+    /// was never edited), with the ids of the messages it replied with, if any
+    pub async fn mark_telegram_msg_handled(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        edit_date: i64,
+        convo_id: Option<i64>,
+        reply_message_ids: &[i64],
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO telegram_replies (chat_id, message_id, edit_date, convo_id, reply_message_ids, created_at) VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (chat_id, message_id) DO UPDATE SET
+                edit_date = excluded.edit_date, convo_id = excluded.convo_id,
+                reply_message_ids = excluded.reply_message_ids",
+        )
+        .bind(chat_id)
+        .bind(message_id)
+        .bind(edit_date)
+        .bind(convo_id)
+        .bind(serde_json::to_string(reply_message_ids)?)
+        .bind(unix_now())
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Notes messages the bot sent to a Telegram chat on its own, not in reply to a message it
+    /// is handling, so that the user's replies to them find the conversation
+    pub async fn record_telegram_replies(
+        &self,
+        chat_id: i64,
+        convo_id: i64,
+        reply_message_ids: &[i64],
+    ) -> anyhow::Result<()> {
+        // keyed by the bot's own first message, which no user message can clash with
+        let Some(first_id) = reply_message_ids.first() else {
+            return Ok(());
+        };
+        self.mark_telegram_msg_handled(chat_id, *first_id, 0, Some(convo_id), reply_message_ids)
+            .await
+    }
+
+    /// Returns the conversation a message the bot sent to a Telegram chat belongs to
+    pub async fn telegram_reply_convo(
+        &self,
+        chat_id: i64,
+        reply_message_id: i64,
+    ) -> anyhow::Result<Option<i64>> {
+        let row = sqlx::query(
+            "SELECT convo_id FROM telegram_replies, json_each(telegram_replies.reply_message_ids)
+            WHERE chat_id = ? AND json_each.value = ? AND convo_id IS NOT NULL",
+        )
+        .bind(chat_id)
+        .bind(reply_message_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    /// Stores an action awaiting confirmation by the requester who asked for it, replacing any older
+    /// one of theirs in the conversation
+    pub async fn insert_pending_action(
+        &self,
+        convo_id: i64,
+        requester_id: &str,
+        action: &str,
+        args: &Value,
+        model: Option<&str>,
+        ttl_secs: i64,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query("DELETE FROM pending_actions WHERE expires_at < ?")
+            .bind(unix_now())
+            .execute(&mut tx)
+            .await?;
+        if let Some(pending_id) = self
+            .find_pending_action(&mut tx, convo_id, requester_id)
+            .await?
+        {
+            sqlx::query("DELETE FROM pending_actions WHERE pending_id = ?")
+                .bind(pending_id)
+                .execute(&mut tx)
+                .await?;
+        }
+        sqlx::query("INSERT INTO pending_actions (convo_id, requester_id, action, args, expires_at, model) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(convo_id)
+            .bind(self.seal(requester_id)?)
+            .bind(action)
+            .bind(self.seal(&args.to_string())?)
+            .bind(unix_now() + ttl_secs)
+            .bind(model)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Removes and returns the unexpired action awaiting confirmation by a requester in a
+    /// conversation, as (action, args, model that proposed it). Actions awaiting confirmation by
+    /// others in the conversation are left alone.
+    pub async fn take_pending_action(
+        &self,
+        convo_id: i64,
+        requester_id: &str,
+    ) -> anyhow::Result<Option<(String, Value, Option<String>)>> {
+        let mut tx = self.db_pool.begin().await?;
+        let Some(pending_id) = self
+            .find_pending_action(&mut tx, convo_id, requester_id)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let row = sqlx::query(
+            "DELETE FROM pending_actions WHERE pending_id = ? RETURNING action, args, expires_at, model",
+        )
+        .bind(pending_id)
+        .fetch_one(&mut tx)
+        .await?;
+        tx.commit().await?;
+        if row.get::<i64, _>("expires_at") < unix_now() {
+            return Ok(None);
+        }
+        let args = serde_json::from_str(&self.open(row.get("args"))?)?;
+        Ok(Some((row.get("action"), args, row.get("model"))))
+    }
+
+    /// Finds the action awaiting confirmation by a requester in a conversation. Requesters are
+    /// encrypted with a random nonce, so they can only be compared once decrypted.
+    async fn find_pending_action(
+        &self,
+        conn: &mut SqliteConnection,
+        convo_id: i64,
+        requester_id: &str,
+    ) -> anyhow::Result<Option<i64>> {
+        let rows =
+            sqlx::query("SELECT pending_id, requester_id FROM pending_actions WHERE convo_id = ?")
+                .bind(convo_id)
+                .fetch_all(conn)
+                .await?;
+        for row in rows {
+            if self.open(row.get("requester_id"))? == requester_id {
+                return Ok(Some(row.get("pending_id")));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Notes that a requester asked for an action just now, and whether a limit turned them away
+    pub async fn insert_action_attempt(
+        &self,
+        action: &str,
+        requester_id: &str,
+        limited: bool,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO action_attempts (action, requester_key, created_at, limited) VALUES (?, ?, ?, ?)",
+        )
+        .bind(action)
+        .bind(self.opaque_key(requester_id))
+        .bind(unix_now())
+        .bind(limited)
+        .execute(&self.db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Counts the attempts at an action in the last `secs` seconds, by one requester or by everyone,
+    /// that were let through or, with `limited`, turned away
+    pub async fn count_action_attempts(
+        &self,
+        action: &str,
+        requester_id: Option<&str>,
+        secs: i64,
+        limited: bool,
+    ) -> anyhow::Result<i64> {
+        let count = sqlx::query(
+            "SELECT COUNT(*) FROM action_attempts
+            WHERE action = ?1 AND created_at > ?2 AND (?3 IS NULL OR requester_key = ?3)
+                AND limited = ?4",
+        )
+        .bind(action)
+        .bind(unix_now() - secs)
+        .bind(requester_id.map(|id| self.opaque_key(id)))
+        .bind(limited)
+        .fetch_one(&self.db_pool)
+        .await?
+        .get(0);
+        Ok(count)
+    }
+
+    /// Stores an action awaiting the admin's approval, returning its id
+    pub async fn insert_approval(&self, approval: &Approval) -> anyhow::Result<i64> {
+        let approval_id = sqlx::query(
+            "INSERT INTO approvals (convo_id, action, args, requester, model, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(approval.convo_id)
+        .bind(&approval.action)
+        .bind(self.seal(&approval.args.to_string())?)
+        .bind(self.seal(&approval.requester.to_string())?)
+        .bind(&approval.model)
+        .bind(unix_now())
+        .execute(&self.db_pool)
+        .await?
+        .last_insert_rowid();
+        Ok(approval_id)
+    }
+
+    /// Records the admin's decision on a pending approval and returns it.
+    /// Returns None if there is no such approval, or it was already decided.
+    pub async fn decide_approval(
+        &self,
+        approval_id: i64,
+        approved: bool,
+        decided_by: &str,
+    ) -> anyhow::Result<Option<Approval>> {
+        let row = sqlx::query(
+            "UPDATE approvals SET status = ?, decided_by = ?, decided_at = ?
+            WHERE approval_id = ? AND status = 'pending'
+            RETURNING approval_id, convo_id, action, args, requester, model",
+        )
+        .bind(if approved { "approved" } else { "rejected" })
+        .bind(decided_by)
+        .bind(unix_now())
+        .bind(approval_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Approval {
+            approval_id: row.get("approval_id"),
+            convo_id: row.get("convo_id"),
+            action: row.get("action"),
+            args: serde_json::from_str(&self.open(row.get("args"))?)?,
+            requester: serde_json::from_str(&self.open(row.get("requester"))?)?,
+            model: row.get("model"),
+        }))
+    }
+
+    /// Adds an entry to the action audit log, returning its id
+    pub async fn insert_audit_entry(&self, entry: &AuditEntry) -> anyhow::Result<i64> {
+        let audit_id = sqlx::query(
+            "INSERT INTO action_audit (action, args, convo_id, platform, requester_id, requester, model, created_at, outcome, rows_affected)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&entry.action)
+        .bind(self.seal(&entry.args.to_string())?)
+        .bind(entry.convo_id)
+        .bind(&entry.platform)
+        .bind(self.seal(&entry.requester_id)?)
+        .bind(self.seal(&entry.requester)?)
+        .bind(&entry.model)
+        .bind(unix_now())
+        .bind(&entry.outcome)
+        .bind(entry.rows_affected)
+        .execute(&self.db_pool)
+        .await?
+        .last_insert_rowid();
+        Ok(audit_id)
+    }
+
+    /// Stores the prior state an audited action can be undone with
+    pub async fn set_undo_data(&self, audit_id: i64, undo_data: &Value) -> anyhow::Result<()> {
+        sqlx::query("UPDATE action_audit SET undo_data = ? WHERE audit_id = ?")
+            .bind(self.seal(&undo_data.to_string())?)
+            .bind(audit_id)
+            .execute(&self.db_pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes and returns the undo data of an audited action together with the action's entry,
+    /// so that it can only be undone once. Returns None if the action cannot be undone (anymore).
+    pub async fn take_undo_data(
+        &self,
+        audit_id: i64,
+    ) -> anyhow::Result<Option<(AuditEntry, Value)>> {
+        let row = sqlx::query(
+            "UPDATE action_audit SET undo_data = NULL WHERE audit_id = ? AND undo_data IS NOT NULL
+            RETURNING audit_id, action, args, convo_id, platform, requester_id, requester, undo_data",
+        )
+        .bind(audit_id)
+        .fetch_optional(&self.db_pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let open = |column: &str| -> anyhow::Result<String> {
+            self.open(row.get::<Option<String>, _>(column).unwrap_or_default())
+        };
+        let entry = AuditEntry {
+            audit_id: row.get("audit_id"),
+            action: row.get("action"),
+            args: serde_json::from_str(&open("args")?).unwrap_or(Value::Null),
+            convo_id: row.get("convo_id"),
+            platform: row.get("platform"),
+            requester_id: open("requester_id")?,
+            requester: open("requester")?,
+            ..Default::default()
+        };
+        Ok(Some((entry, serde_json::from_str(&open("undo_data")?)?)))
+    }
+
+    /// Returns the newest audit log entries, optionally only those of one conversation or action
+    pub async fn get_audit_entries(
+        &self,
+        convo_id: Option<i64>,
+        action: Option<&str>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<AuditEntry>> {
+        let rows = sqlx::query(
+            "SELECT audit_id, action, args, convo_id, platform, requester_id, requester, model,
+                datetime(created_at, 'unixepoch') AS date, outcome, rows_affected
+            FROM action_audit
+            WHERE (?1 IS NULL OR convo_id = ?1) AND (?2 IS NULL OR action = ?2)
+            ORDER BY audit_id DESC LIMIT ?3",
+        )
+        .bind(convo_id)
+        .bind(action)
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                let open = |column: &str| -> anyhow::Result<String> {
+                    self.open(row.get::<Option<String>, _>(column).unwrap_or_default())
+                };
+                let args = open("args")?;
+                Ok(AuditEntry {
+                    audit_id: row.get("audit_id"),
+                    action: row.get("action"),
+                    args: serde_json::from_str(&args).unwrap_or(Value::Null),
+                    convo_id: row.get("convo_id"),
+                    platform: row.get("platform"),
+                    requester_id: open("requester_id")?,
+                    requester: open("requester")?,
+                    model: row.get("model"),
+                    date: row.get("date"),
+                    outcome: row.get("outcome"),
+                    rows_affected: row.get("rows_affected"),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the ids and platforms of all conversations matching the filter, oldest first
+    pub async fn filter_convos(
+        &self,
+        filter: &ConvoFilter,
+    ) -> anyhow::Result<Vec<(i64, Option<String>)>> {
+        let rows = sqlx::query(
+            "SELECT convo_id, platform,
+                (SELECT MIN(created_at) FROM messages WHERE messages.convo_id = conversations.convo_id) AS started_at
+            FROM conversations
+            WHERE (?1 IS NULL OR platform = ?1)
+            AND (?2 IS NULL OR started_at >= CAST(strftime('%s', ?2) AS INTEGER))
+            AND (?3 IS NULL OR started_at < CAST(strftime('%s', ?3) AS INTEGER))
+            AND (NOT ?4 OR (
+                EXISTS (SELECT 1 FROM ratings WHERE ratings.convo_id = conversations.convo_id AND rating > 0)
+                AND NOT EXISTS (SELECT 1 FROM ratings WHERE ratings.convo_id = conversations.convo_id AND rating < 0)
+            ))
+            AND (NOT ?5 OR NOT EXISTS (SELECT 1 FROM action_audit WHERE action_audit.convo_id = conversations.convo_id))
+            ORDER BY started_at",
+        )
+        .bind(filter.platform.map(|platform| platform.to_string()))
+        .bind(&filter.since)
+        .bind(&filter.until)
+        .bind(filter.rated_positive)
+        .bind(filter.exclude_actions)
+        .fetch_all(&self.db_pool)
+        .await?;
+
         Ok(rows
             .iter()
-            .map(|row| (row.get("sender"), row.get("text")))
+            .map(|row| (row.get("convo_id"), row.get("platform")))
             .collect())
     }
+
+    /// Full-text searches all stored messages, best matches first
+    pub async fn search_msgs(&self, query: &str, limit: u32) -> anyhow::Result<Vec<SearchHit>> {
+        let fts_query = match &self.cipher {
+            Some(cipher) => fts_query(&cipher.blind_words(query)),
+            None => fts_query(query),
+        };
+        let rows = sqlx::query(
+            "SELECT messages.convo_id, conversations.platform,
+                datetime(messages.created_at, 'unixepoch') AS date,
+                messages.text
+            FROM messages_fts
+            JOIN messages ON messages.rowid = messages_fts.rowid
+            LEFT JOIN conversations ON conversations.convo_id = messages.convo_id
+            WHERE messages_fts MATCH ?
+            ORDER BY rank
+            LIMIT ?",
+        )
+        .bind(fts_query)
+        .bind(limit)
+        .fetch_all(&self.db_pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                Ok(SearchHit {
+                    convo_id: row.get("convo_id"),
+                    platform: row.get("platform"),
+                    date: row.get("date"),
+                    snippet: snippet(&self.open(row.get("text"))?, query),
+                })
+            })
+            .collect()
+    }
+
+    /// Re-encrypts every value in `SEALED_COLUMNS` with the current key, including anything stored
+    /// before encryption was enabled, and rebuilds the search index.
+    /// Returns the number of values rewritten.
+    pub async fn reencrypt_all(&self) -> anyhow::Result<usize> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .context("no history_key configured to encrypt with")?;
+        let mut tx = self.db_pool.begin().await?;
+        let mut count = 0;
+        for (table, column, key_column) in SEALED_COLUMNS {
+            count += reencrypt_column(&mut tx, cipher, table, column, *key_column).await?;
+        }
+
+        // the hashed words in the index depend on the key
+        rebuild_search_index(&mut tx, Some(cipher)).await?;
+        tx.commit().await?;
+        Ok(count)
+    }
+
+    /// Encrypts a value for storage, if encryption is enabled
+    fn seal(&self, plaintext: &str) -> anyhow::Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(plaintext),
+            None => Ok(plaintext.to_owned()),
+        }
+    }
+
+    /// Decrypts a stored value, if it is encrypted
+    fn open(&self, stored: String) -> anyhow::Result<String> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(&stored),
+            None if is_encrypted(&stored) => {
+                anyhow::bail!("chat history is encrypted, but no history_key is configured")
+            }
+            None => Ok(stored),
+        }
+    }
+
+    /// Identifies a value, such as a requester's Telegram id or email address, without storing it,
+    /// if encryption is enabled
+    fn opaque_key(&self, value: &str) -> String {
+        self.lookup_key(value).unwrap_or_else(|| value.to_owned())
+    }
+
+    /// The blind index of a value, if encryption is enabled
+    fn lookup_key(&self, plaintext: &str) -> Option<String> {
+        self.cipher
+            .as_ref()
+            .map(|cipher| cipher.blind_index(plaintext))
+    }
+
+    /// The blind indexes a value may have been stored under, as a JSON array: one for each history
+    /// key, since rows keep the index of the key they were stored with until `rotate-key` runs
+    fn lookup_keys(&self, plaintext: &str) -> String {
+        let keys = self
+            .cipher
+            .as_ref()
+            .map(|cipher| cipher.blind_indexes(plaintext))
+            .unwrap_or_default();
+        Value::from(keys).to_string()
+    }
+}
+
+/// A job taken from the queue
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub job_id: i64,
+    pub kind: String,
+    pub payload: String,
+    /// how many times the job has been started, including this time
+    pub attempts: i64,
+    /// how far earlier attempts got in replying, if they got as far as generating a reply
+    pub progress: Option<ReplyProgress>,
+}
+
+/// A job's reply and how much of it was sent, saved so that a retry sends the rest of the same
+/// reply instead of responding again
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReplyProgress {
+    /// empty if the bot does not reply
+    pub text: String,
+    /// the stored reply, if users can rate it
+    pub rated_msg_id: Option<i64>,
+    /// whether the reply is part of the conversation. Replies to admin commands are not, and are
+    /// sent as they are instead of rendering markdown.
+    pub in_convo: bool,
+    /// the Telegram messages sent so far, one for each part of the reply
+    pub sent_ids: Vec<i64>,
+    /// whether an email reply was sent
+    pub emailed: bool,
+}
+
+/// An action waiting for the admin's approval
+#[derive(Clone, Debug, Default)]
+pub struct Approval {
+    /// set by the database
+    pub approval_id: i64,
+    pub convo_id: i64,
+    pub action: String,
+    pub args: Value,
+    /// who asked for the action, and where to tell them how it went
+    pub requester: Value,
+    pub model: Option<String>,
+}
+
+/// An entry in the action audit log
+#[derive(Clone, Debug, Default)]
+pub struct AuditEntry {
+    /// set by the database
+    pub audit_id: i64,
+    pub action: String,
+    pub args: Value,
+    pub convo_id: Option<i64>,
+    pub platform: Option<String>,
+    /// Telegram user id or email address of whoever the bot was talking to
+    pub requester_id: String,
+    /// human-readable name of the requester
+    pub requester: String,
+    pub model: Option<String>,
+    /// set by the database
+    pub date: Option<String>,
+    /// "success", "failed: <error>", "awaiting_confirmation", "awaiting_approval", ...
+    pub outcome: String,
+    pub rows_affected: Option<i64>,
+}
+
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} | {} {} | convo {} ({}) | requested by {} | model {} | {} | {} rows",
+            self.audit_id,
+            self.date.as_deref().unwrap_or("unknown date"),
+            self.action,
+            self.args,
+            self.convo_id
+                .map_or("unknown".to_owned(), |id| id.to_string()),
+            self.platform.as_deref().unwrap_or("unknown platform"),
+            self.requester,
+            self.model.as_deref().unwrap_or("unknown"),
+            self.outcome,
+            self.rows_affected
+                .map_or("?".to_owned(), |rows| rows.to_string()),
+        )
+    }
+}
+
+/// Criteria for selecting conversations, e.g. for exporting them
+#[derive(Clone, Debug, Default)]
+pub struct ConvoFilter {
+    /// only conversations on this platform
+    pub platform: Option<Platform>,
+    /// only conversations started on or after this date (YYYY-MM-DD)
+    pub since: Option<String>,
+    /// only conversations started before this date (YYYY-MM-DD)
+    pub until: Option<String>,
+    /// only conversations with positive and no negative ratings. Ratings come from the Telegram
+    /// feedback buttons, so this matches no email conversations.
+    pub rated_positive: bool,
+    /// skip conversations in which the bot performed an action
+    pub exclude_actions: bool,
+}
+
+/// A message matching a full-text search
+#[derive(Clone, Debug)]
+pub struct SearchHit {
+    pub convo_id: i64,
+    pub platform: Option<String>,
+    pub date: Option<String>,
+    pub snippet: String,
+}
+
+impl std::fmt::Display for SearchHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[convo {} | {} | {}] {}",
+            self.convo_id,
+            self.platform.as_deref().unwrap_or("unknown platform"),
+            self.date.as_deref().unwrap_or("unknown date"),
+            self.snippet
+        )
+    }
+}
+
+/// How the bot handled a Telegram message
+#[derive(Clone, Debug, Default)]
+pub struct TelegramReply {
+    /// None for admin commands, which are not part of a conversation
+    pub convo_id: Option<i64>,
+    /// empty if the bot did not reply
+    pub reply_message_ids: Vec<i64>,
+    /// the edit of the message that was handled, or 0 for the original
+    pub edit_date: i64,
+    /// the job that replied, or is replying, to the message
+    pub job_id: Option<i64>,
+}
+
+/// What a reply of the bot's was written by, for its ratings
+#[derive(Clone, Copy, Debug)]
+pub struct ReplyOrigin<'a> {
+    pub model: &'a str,
+    /// the ids of the learned facts in the model's prompt
+    pub facts_used: &'a [i64],
+}
+
+/// What ratings are grouped by in a report
+#[derive(Clone, Copy, Debug)]
+pub enum RatingGroup {
+    Day,
+    Model,
+    /// the learned facts that were in the prompt of the rated reply
+    Fact,
+}
+
+/// How users rated the bot's replies on one day, from one model, or given one fact
+#[derive(Clone, Debug)]
+pub struct RatingCounts {
+    pub label: String,
+    pub helpful: i64,
+    pub unhelpful: i64,
+    /// how often users asked for a human instead
+    pub human: i64,
+}
+
+impl std::fmt::Display for RatingCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: 👍 {} | 👎 {} | human {}",
+            self.label, self.helpful, self.unhelpful, self.human
+        )
+    }
+}
+
+/// Turns free text into an FTS5 query matching all of its words, so that punctuation
+/// in what staff type is never parsed as query syntax
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What goes into the full-text index for a message
+fn index_text(cipher: Option<&HistoryCipher>, text: &str) -> String {
+    match cipher {
+        Some(cipher) => cipher.blind_words(text),
+        None => text.to_owned(),
+    }
+}
+
+/// Re-indexes every message from scratch
+async fn rebuild_search_index(
+    conn: &mut SqliteConnection,
+    cipher: Option<&HistoryCipher>,
+) -> anyhow::Result<()> {
+    conn.execute("INSERT INTO messages_fts(messages_fts) VALUES ('delete-all')")
+        .await?;
+    let rows = sqlx::query("SELECT rowid, text FROM messages")
+        .fetch_all(&mut *conn)
+        .await?;
+    for row in rows {
+        let stored: String = row.get("text");
+        let text = match cipher {
+            Some(cipher) => cipher.decrypt(&stored)?,
+            None if is_encrypted(&stored) => continue,
+            None => stored,
+        };
+        sqlx::query("INSERT INTO messages_fts (rowid, text) VALUES (?, ?)")
+            .bind(row.get::<i64, _>("rowid"))
+            .bind(index_text(cipher, &text))
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Cuts out the part of a message around the first word matching the query, with matching
+/// words in [brackets]
+fn snippet(text: &str, query: &str) -> String {
+    const BEFORE: usize = 6;
+    const LEN: usize = 16;
+    let query_words: Vec<String> = words(query).collect();
+    let matches = |token: &str| words(token).any(|word| query_words.contains(&word));
+
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let first = tokens.iter().position(|t| matches(t)).unwrap_or(0);
+    let start = first.saturating_sub(BEFORE);
+    let end = (start + LEN).min(tokens.len());
+    let mut snippet = tokens[start..end]
+        .iter()
+        .map(|t| {
+            if matches(t) {
+                format!("[{t}]")
+            } else {
+                t.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    if start > 0 {
+        snippet = format!("...{snippet}");
+    }
+    if end < tokens.len() {
+        snippet += "...";
+    }
+    snippet
+}
+
+/// Notes which model wrote a reply of the bot's, and which learned facts it was given
+async fn set_reply_origin(
+    conn: &mut SqliteConnection,
+    msg_id: i64,
+    origin: &ReplyOrigin<'_>,
+) -> anyhow::Result<()> {
+    sqlx::query("UPDATE messages SET model = ?, facts_used = ? WHERE rowid = ?")
+        .bind(origin.model)
+        .bind(serde_json::to_string(origin.facts_used)?)
+        .bind(msg_id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Columns that hold values encrypted with the history key, and the column holding each value's
+/// blind index, if it has one. Besides the chat history itself, this is everything that can
+/// identify users or their accounts: queued messages and replies, action args, and requesters.
+const SEALED_COLUMNS: &[(&str, &str, Option<&str>)] = &[
+    ("messages", "text", Some("text_key")),
+    ("conversations", "metadata", Some("metadata_key")),
+    ("jobs", "payload", None),
+    ("jobs", "progress", None),
+    ("pending_actions", "requester_id", None),
+    ("pending_actions", "args", None),
+    ("action_audit", "args", None),
+    ("action_audit", "requester_id", None),
+    ("action_audit", "requester", None),
+    ("action_audit", "undo_data", None),
+    ("approvals", "args", None),
+    ("approvals", "requester", None),
+];
+
+/// Re-encrypts the values of a column that are not encrypted with the current key yet, along with
+/// their blind index. Returns the number of values re-encrypted.
+async fn reencrypt_column(
+    conn: &mut SqliteConnection,
+    cipher: &HistoryCipher,
+    table: &str,
+    column: &str,
+    key_column: Option<&str>,
+) -> anyhow::Result<usize> {
+    let rows = sqlx::query(&format!(
+        "SELECT rowid, CAST({column} AS TEXT) AS value FROM {table}"
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut count = 0;
+    for row in rows {
+        let Some(stored) = row.get::<Option<String>, _>("value") else {
+            continue;
+        };
+        if cipher.is_current(&stored) {
+            continue;
+        }
+        let plaintext = cipher.decrypt(&stored)?;
+        let rowid: i64 = row.get("rowid");
+        match key_column {
+            Some(key_column) => {
+                sqlx::query(&format!(
+                    "UPDATE {table} SET {column} = ?, {key_column} = ? WHERE rowid = ?"
+                ))
+                .bind(cipher.encrypt(&plaintext)?)
+                .bind(cipher.blind_index(&plaintext))
+                .bind(rowid)
+                .execute(&mut *conn)
+                .await?
+            }
+            None => {
+                sqlx::query(&format!("UPDATE {table} SET {column} = ? WHERE rowid = ?"))
+                    .bind(cipher.encrypt(&plaintext)?)
+                    .bind(rowid)
+                    .execute(&mut *conn)
+                    .await?
+            }
+        };
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Adds a column to a table created by an older version of the bot
+async fn ensure_column(
+    conn: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> anyhow::Result<()> {
+    let exists = sqlx::query("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some();
+    if !exists {
+        conn.execute(format!("ALTER TABLE {table} ADD COLUMN {column} {decl}").as_str())
+            .await?;
+    }
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
 }
 
 // TODO!
@@ -2,17 +2,19 @@ use std::{collections::HashMap, time::Duration};
 
 use anyhow::Context;
 use async_compat::CompatExt;
+use base64::{engine::general_purpose, Engine};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use reqwest::header;
 use serde_json::{json, Value};
 use smol::lock::Semaphore;
 use smol_timeout::TimeoutExt;
-use warp::Filter;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::WithStatus, Filter};
 
 use crate::{
-    database::{Platform, Role},
-    responder::respond,
+    database::{Job, Platform},
+    queue::{enqueue_job, JobKind},
+    responder::{respond, ReplyTarget, Requester},
     Message, CONFIG, DB,
 };
 
@@ -26,20 +28,26 @@ struct ParsedEmail {
     date: String,
 }
 
-pub async fn handle_email() -> () {
-    let lol = warp::path("support-bot-email").and(warp::body::form()).then(
-         |email: HashMap<String, String>| async move {
-            match handle_email_inner(email).await {
-                Ok(()) => "Success".to_owned(),
-                Err(err) => format!("Our email bot encountered an error! {:?}\nPlease try sending your email again!", err),
+/// Receives emails from Mailgun and puts them into the job queue, so that Mailgun gets its
+/// answer right away and no email is lost if the bot goes down while responding
+pub fn email_route() -> BoxedFilter<(WithStatus<&'static str>,)> {
+    warp::path("support-bot-email")
+        .and(warp::body::form())
+        .then(|email: HashMap<String, String>| async move {
+            match enqueue_job(JobKind::Email, &json!(email)).await {
+                Ok(()) => warp::reply::with_status("Success", StatusCode::OK),
+                Err(err) => {
+                    // mailgun retries the email later
+                    log::error!("cannot queue email: {:?}", err);
+                    warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR)
+                }
             }
-        },
-    );
-
-    warp::serve(lol).run(([0, 0, 0, 0], 3030)).compat().await;
+        })
+        .boxed()
 }
 
-async fn handle_email_inner(email: HashMap<String, String>) -> anyhow::Result<()> {
+/// Responds to a single email taken from the job queue
+pub async fn process_email(job: &Job, email: HashMap<String, String>) -> anyhow::Result<()> {
     let parsed_email = parse_email(email)?;
     log::debug!(
         "title: {}\nbody: {}\nsender_name: {}\nsender_email: {}\nmessage_id: {}",
@@ -50,57 +58,117 @@ async fn handle_email_inner(email: HashMap<String, String>) -> anyhow::Result<()
         parsed_email.message_id,
     );
 
-    let msg = Message {
-        text: parsed_email.title.clone() + ": " + &parsed_email.body, // text = title + email body
-        convo_id: get_convo_id(make_email_metadata(&parsed_email.sender_email)).await?, // convo_id = sender email address
+    let mut progress = match job.progress.clone() {
+        // an earlier attempt already responded, but did not get the email out
+        Some(progress) => progress,
+        None => {
+            let msg = Message {
+                text: parsed_email.title.clone() + ": " + &parsed_email.body, // text = title + email body
+                convo_id: get_convo_id(make_email_metadata(&parsed_email.sender_email)).await?, // convo_id = sender email address
+            };
+            let requester = Requester {
+                platform: Platform::Email,
+                id: parsed_email.sender_email.clone(),
+                name: parsed_email.sender_name.clone(),
+                reply_to: ReplyTarget::Email {
+                    subject: parsed_email.title.clone(),
+                    message_id: parsed_email.message_id.clone(),
+                },
+            };
+            let reply = respond(msg.clone(), &requester)
+                .await
+                .context("cannot calculate response")?;
+            if reply.text.is_empty() {
+                return Ok(());
+            }
+            let resp = format!(
+                "{}\n\n{}\n\n> ------- Original Message -------\n> On {}, {} <{}> wrote:\n> \n> {}",
+                reply.text,
+                &CONFIG.email_config.as_ref().unwrap().signature,
+                parsed_email.date,
+                parsed_email.sender_name,
+                parsed_email.sender_email,
+                parsed_email.body.replace("\n", "\n> ")
+            );
+
+            // add question & response to db
+            DB.store_reply(
+                job.job_id,
+                Some(&msg),
+                &Message {
+                    text: resp,
+                    convo_id: msg.convo_id,
+                },
+                Platform::Email,
+                make_email_metadata(&parsed_email.sender_email),
+                reply.origin(),
+            )
+            .await?
+        }
     };
-    let resp = respond(msg.clone())
-        .await
-        .context("cannot calculate response")?;
-
-    if resp != "".to_string() {
-        let resp = format!(
-            "{}\n\n{}\n\n> ------- Original Message -------\n> On {}, {} <{}> wrote:\n> \n> {}",
-            resp,
-            &CONFIG.email_config.as_ref().unwrap().signature,
-            parsed_email.date,
-            parsed_email.sender_name,
-            parsed_email.sender_email,
-            parsed_email.body.replace("\n", "\n> ")
-        );
-
-        // add question & response to db
-        DB.insert_msg(
-            &msg,
-            Platform::Email,
-            Role::User,
-            make_email_metadata(&parsed_email.sender_email),
-        )
-        .await?;
-        DB.insert_msg(
-            &Message {
-                text: resp.clone(),
-                convo_id: msg.convo_id,
-            },
-            Platform::Email,
-            Role::Assistant,
-            make_email_metadata(&parsed_email.sender_email),
-        )
-        .await?;
 
-        // send email response
+    // send email response
+    if !progress.emailed {
         send_email(
             &("RE: ".to_owned() + &parsed_email.title),
-            &resp,
+            &progress.text,
             &parsed_email.sender_email,
             Some(&parsed_email.message_id),
         )
         .await?;
+        progress.emailed = true;
+        DB.save_job_progress(job.job_id, &progress).await?;
     }
 
     Ok(())
 }
 
+/// Emails a notification job's message to the user in an existing conversation, in the thread of
+/// their email with the given subject and message id, and adds the email to the conversation
+pub async fn send_followup(
+    job: &Job,
+    convo_id: i64,
+    to: &str,
+    subject: &str,
+    in_reply_to: &str,
+    text: &str,
+) -> anyhow::Result<()> {
+    let mut progress = match job.progress.clone() {
+        Some(progress) => progress,
+        None => {
+            let body = format!(
+                "{}\n\n{}",
+                text,
+                &CONFIG.email_config.as_ref().unwrap().signature
+            );
+            DB.store_reply(
+                job.job_id,
+                None,
+                &Message {
+                    text: body,
+                    convo_id,
+                },
+                Platform::Email,
+                make_email_metadata(to),
+                None,
+            )
+            .await?
+        }
+    };
+    if !progress.emailed {
+        send_email(
+            &("RE: ".to_owned() + subject),
+            &progress.text,
+            to,
+            Some(in_reply_to),
+        )
+        .await?;
+        progress.emailed = true;
+        DB.save_job_progress(job.job_id, &progress).await?;
+    }
+    Ok(())
+}
+
 fn parse_email(email: HashMap<String, String>) -> anyhow::Result<ParsedEmail> {
     let title = email
         .get("subject")
@@ -188,20 +256,92 @@ pub async fn send_email(
 
     log::debug!("params = {:?}", params);
 
-    let base64_uname_pwd = base64::encode(format!(
+    let base64_uname_pwd = general_purpose::STANDARD.encode(format!(
         "api:{}",
         CONFIG.email_config.as_ref().unwrap().mailgun_key
     ));
     let auth_value = format!("Basic {}", base64_uname_pwd);
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post(&CONFIG.email_config.as_ref().unwrap().mailgun_url)
-        .header(header::AUTHORIZATION, auth_value)
-        .form(&params)
-        .send()
-        .timeout(Duration::from_secs(10))
-        .await;
-    log::debug!("response from mailgun: {:?}", res);
-    Ok(())
+    post_mailgun(
+        &CONFIG.email_config.as_ref().unwrap().mailgun_url,
+        &auth_value,
+        &params,
+    )
+    .await
+}
+
+/// Posts a form to Mailgun. reqwest needs a tokio runtime, which the job worker's tasks do not run
+/// on, so the request goes through async_compat.
+async fn post_mailgun(
+    url: &str,
+    auth_value: &str,
+    params: &[(String, String)],
+) -> anyhow::Result<()> {
+    async {
+        let res = reqwest::Client::new()
+            .post(url)
+            .header(header::AUTHORIZATION, auth_value)
+            .form(params)
+            .send()
+            .timeout(Duration::from_secs(10))
+            .await
+            .context("mailgun timed out")??;
+        log::debug!("response from mailgun: {:?}", res);
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.unwrap_or_default();
+            anyhow::bail!("mailgun returned {status}: {body}")
+        }
+        Ok(())
+    }
+    .compat()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers one HTTP request with the given status line, and returns the request
+    fn serve_once(status: &'static str) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/messages", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).unwrap();
+            let body = "queued";
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn posts_outside_tokio() {
+        let (url, server) = serve_once("200 OK");
+        let params = [("to".to_owned(), "user@example.com".to_owned())];
+        // like the job worker, outside any tokio runtime
+        smol::future::block_on(post_mailgun(&url, "Basic abc", &params)).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.contains("authorization: Basic abc"));
+    }
+
+    #[test]
+    fn returns_mailgun_errors() {
+        let (url, server) = serve_once("400 Bad Request");
+        let err = smol::future::block_on(post_mailgun(&url, "Basic abc", &[])).unwrap_err();
+        server.join().unwrap();
+        assert!(format!("{err}").contains("queued"), "{err}");
+    }
 }
@@ -0,0 +1,238 @@
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::CONFIG;
+
+/// Environment variable holding the history key, used when `history_key` is not in the config
+const HISTORY_KEY_ENV: &str = "GEPH_SUPPORT_BOT_HISTORY_KEY";
+
+/// Prefix of every encrypted value, followed by `<key id>:<base64 nonce and ciphertext>`
+const ENCRYPTED_PREFIX: &str = "enc1:";
+
+const NONCE_LEN: usize = 12;
+
+/// Encrypts chat history before it is stored, and computes keyed hashes ("blind indexes") so
+/// that encrypted values can still be looked up by equality and searched by word.
+pub struct HistoryCipher {
+    current: HistoryKey,
+    old: Vec<HistoryKey>,
+}
+
+struct HistoryKey {
+    id: String,
+    cipher: ChaCha20Poly1305,
+    index_key: Vec<u8>,
+}
+
+impl HistoryKey {
+    fn from_base64(b64: &str) -> anyhow::Result<Self> {
+        let key = general_purpose::STANDARD
+            .decode(b64.trim())
+            .context("history key is not valid base64")?;
+        if key.len() != 32 {
+            anyhow::bail!("history key must be 32 bytes, got {}", key.len())
+        }
+        let id = hex(&Sha256::digest(&key)[..4]);
+        let index_key = hmac_sha256(&key, b"blind index");
+        Ok(Self {
+            id,
+            cipher: ChaCha20Poly1305::new_from_slice(&key)?,
+            index_key,
+        })
+    }
+}
+
+impl HistoryCipher {
+    /// Loads the keys from the config or the environment. Returns None if history should be
+    /// stored unencrypted.
+    pub fn from_config() -> anyhow::Result<Option<Self>> {
+        match CONFIG
+            .history_key
+            .clone()
+            .or_else(|| std::env::var(HISTORY_KEY_ENV).ok())
+        {
+            Some(key) => Ok(Some(Self::from_keys(&key, &CONFIG.old_history_keys)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Loads base64-encoded keys: the current one, and old ones that are only used for reading
+    fn from_keys(current: &str, old: &[String]) -> anyhow::Result<Self> {
+        let current = HistoryKey::from_base64(current).context("invalid history_key")?;
+        let old = old
+            .iter()
+            .map(|key| HistoryKey::from_base64(key).context("invalid old_history_keys entry"))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { current, old })
+    }
+
+    /// Encrypts a value with the current key
+    pub fn encrypt(&self, plaintext: &str) -> anyhow::Result<String> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.current
+                .cipher
+                .encrypt(&nonce, plaintext.as_bytes())
+                .map_err(|_| anyhow::anyhow!("cannot encrypt history"))?,
+        );
+        Ok(format!(
+            "{ENCRYPTED_PREFIX}{}:{}",
+            self.current.id,
+            general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    /// Decrypts a value encrypted with the current or any old key.
+    /// Values stored before encryption was enabled are returned as they are.
+    pub fn decrypt(&self, stored: &str) -> anyhow::Result<String> {
+        let Some(rest) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(stored.to_owned());
+        };
+        let (key_id, sealed) = rest.split_once(':').context("malformed encrypted value")?;
+        let key = std::iter::once(&self.current)
+            .chain(self.old.iter())
+            .find(|key| key.id == key_id)
+            .with_context(|| {
+                format!("no history key with id {key_id}; is it in old_history_keys?")
+            })?;
+        let sealed = general_purpose::STANDARD.decode(sealed)?;
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!("malformed encrypted value")
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = key
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("cannot decrypt history with key {key_id}"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Whether a stored value is already encrypted with the current key
+    pub fn is_current(&self, stored: &str) -> bool {
+        stored.starts_with(&format!("{ENCRYPTED_PREFIX}{}:", self.current.id))
+    }
+
+    /// A keyed hash of a value, for finding encrypted rows by exact value
+    pub fn blind_index(&self, plaintext: &str) -> String {
+        hex(&hmac_sha256(&self.current.index_key, plaintext.as_bytes()))
+    }
+
+    /// The blind indexes of a value under the current and every old key, for finding rows stored
+    /// before the key was rotated
+    pub fn blind_indexes(&self, plaintext: &str) -> Vec<String> {
+        std::iter::once(&self.current)
+            .chain(self.old.iter())
+            .map(|key| hex(&hmac_sha256(&key.index_key, plaintext.as_bytes())))
+            .collect()
+    }
+
+    /// Replaces every word with a short keyed hash, so the full-text index can match whole words
+    /// without storing them
+    pub fn blind_words(&self, text: &str) -> String {
+        words(text)
+            .map(|word| hex(&hmac_sha256(&self.current.index_key, word.as_bytes())[..8]))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Lowercased words of a text, split the way the full-text index splits them
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+}
+
+/// Whether a stored value is encrypted
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const KEY_B: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    fn cipher(current: &str, old: &[&str]) -> HistoryCipher {
+        let old: Vec<String> = old.iter().map(|key| key.to_string()).collect();
+        HistoryCipher::from_keys(current, &old).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let cipher = cipher(KEY_A, &[]);
+        let stored = cipher.encrypt("user: my account is 1234").unwrap();
+        assert!(is_encrypted(&stored));
+        assert!(!stored.contains("1234"));
+        assert_eq!(cipher.decrypt(&stored).unwrap(), "user: my account is 1234");
+        // every value gets its own nonce
+        assert_ne!(
+            cipher.encrypt("same").unwrap(),
+            cipher.encrypt("same").unwrap()
+        );
+    }
+
+    #[test]
+    fn passes_plaintext_through() {
+        let cipher = cipher(KEY_A, &[]);
+        assert_eq!(
+            cipher.decrypt("stored before encryption").unwrap(),
+            "stored before encryption"
+        );
+        assert!(!cipher.is_current("stored before encryption"));
+    }
+
+    #[test]
+    fn decrypts_with_old_keys() {
+        let stored = cipher(KEY_A, &[]).encrypt("hello").unwrap();
+        let rotated = cipher(KEY_B, &[KEY_A]);
+        assert_eq!(rotated.decrypt(&stored).unwrap(), "hello");
+        assert!(!rotated.is_current(&stored));
+        let restored = rotated.encrypt("hello").unwrap();
+        assert!(rotated.is_current(&restored));
+        // once the old key is dropped, its values cannot be read
+        assert!(cipher(KEY_B, &[]).decrypt(&stored).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_values() {
+        let cipher = cipher(KEY_A, &[]);
+        let stored = cipher.encrypt("hello").unwrap();
+        let (prefix, sealed) = stored.rsplit_once(':').unwrap();
+        let mut sealed = general_purpose::STANDARD.decode(sealed).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        let tampered = format!("{prefix}:{}", general_purpose::STANDARD.encode(sealed));
+        assert!(cipher.decrypt(&tampered).is_err());
+    }
+
+    #[test]
+    fn finds_blind_indexes_of_old_keys() {
+        let old = cipher(KEY_A, &[]);
+        let rotated = cipher(KEY_B, &[KEY_A]);
+        assert_ne!(old.blind_index("a@b.io"), rotated.blind_index("a@b.io"));
+        assert_eq!(
+            rotated.blind_indexes("a@b.io"),
+            [rotated.blind_index("a@b.io"), old.blind_index("a@b.io")]
+        );
+    }
+}
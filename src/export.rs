@@ -0,0 +1,119 @@
+use std::io::Write;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::json;
+
+use crate::{database::ConvoFilter, openai::get_chatbot_prompt, CONFIG, DB};
+
+/// Marks where the email handler starts quoting the user's message below the bot's reply
+const EMAIL_QUOTE_MARKER: &str = "\n\n> ------- Original Message -------";
+
+/// Writes the conversations matching `filter` as OpenAI-style fine-tuning JSONL, one conversation
+/// per line. Returns the number of conversations written.
+pub async fn export_jsonl(
+    filter: &ConvoFilter,
+    include_system_prompt: bool,
+    out: &mut impl Write,
+) -> anyhow::Result<usize> {
+    let system_prompt = if include_system_prompt {
+        Some(
+            get_chatbot_prompt(CONFIG.actions_config.is_some(), None)
+                .await?
+                .0,
+        )
+    } else {
+        None
+    };
+
+    let mut count = 0;
+    for (convo_id, platform) in DB.filter_convos(filter).await? {
+        let history = DB.get_convo_history(convo_id).await?;
+        if !history.iter().any(|(role, _)| role == "assistant") {
+            continue;
+        }
+        let mut messages = vec![];
+        if let Some(prompt) = &system_prompt {
+            messages.push(json!({"role": "system", "content": prompt}));
+        }
+        for (role, text) in history {
+            let text = if role == "assistant" {
+                // quoted emails repeat the user's message and address
+                text.split(EMAIL_QUOTE_MARKER).next().unwrap_or_default()
+            } else {
+                &text
+            };
+            let text = if role == "user" && platform.as_deref() == Some("telegram") {
+                scrub_telegram_sender(text)
+            } else {
+                text.to_owned()
+            };
+            messages.push(json!({"role": role, "content": scrub_pii(&text)}));
+        }
+        writeln!(out, "{}", json!({ "messages": messages }))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Replaces email addresses, phone numbers and Telegram handles with placeholders.
+/// Usernames that users type out in free text cannot be recognized, so exports should still be
+/// reviewed before they leave our hands.
+fn scrub_pii(text: &str) -> String {
+    static EMAIL: Lazy<Regex> = Lazy::new(|| Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").unwrap());
+    static PHONE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\+?\d[\d \-]{7,}\d").unwrap());
+    static HANDLE: Lazy<Regex> = Lazy::new(|| Regex::new(r"@\w{5,32}").unwrap());
+
+    let text = EMAIL.replace_all(text, "[email]");
+    let text = PHONE.replace_all(&text, "[phone]");
+    HANDLE.replace_all(&text, "[username]").into_owned()
+}
+
+/// The Telegram handler prefixes user messages with the sender's username
+fn scrub_telegram_sender(text: &str) -> String {
+    static SENDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\w{5,32}: ").unwrap());
+    SENDER.replace(text, "[username]: ").into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_emails() {
+        assert_eq!(
+            scrub_pii("write to first.last+geph@mail.example.com today"),
+            "write to [email] today"
+        );
+    }
+
+    #[test]
+    fn scrubs_phone_numbers() {
+        assert_eq!(
+            scrub_pii("call +1 555-123-4567 or 0912345678"),
+            "call [phone] or [phone]"
+        );
+        // short numbers such as prices and versions stay
+        assert_eq!(
+            scrub_pii("Plus costs 5 euros in v4.10"),
+            "Plus costs 5 euros in v4.10"
+        );
+    }
+
+    #[test]
+    fn scrubs_handles() {
+        assert_eq!(
+            scrub_pii("ask @geph_support or @ab"),
+            "ask [username] or @ab"
+        );
+    }
+
+    #[test]
+    fn scrubs_telegram_senders() {
+        assert_eq!(
+            scrub_telegram_sender("someuser: my email is a@b.io"),
+            "[username]: my email is a@b.io"
+        );
+        assert_eq!(scrub_telegram_sender("hi: there"), "hi: there");
+    }
+}
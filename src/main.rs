@@ -1,19 +1,30 @@
 mod actions;
+mod admin;
+mod cli;
 mod database;
 mod email;
+mod encryption;
+mod export;
 mod learn;
+mod markdown;
 mod openai;
+mod queue;
 mod responder;
+mod server;
 mod telegram;
 
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
+use actions::ACTIONS;
 use argh::FromArgs;
 use database::ChatHistoryDb;
-use email::handle_email;
+use encryption::HistoryCipher;
 use once_cell::sync::Lazy;
+use queue::run_worker;
+use responder::auto_resolve_loop;
 use serde::{Deserialize, Serialize};
-use telegram::handle_telegram;
+use server::serve;
+use telegram::{handle_telegram, set_webhook};
 
 /// A tool to run the Geph support bot.
 #[derive(FromArgs, PartialEq, Debug)]
@@ -21,12 +32,21 @@ struct Args {
     /// configuration YAML file path
     #[argh(option, short = 'c', long = "config")]
     config: PathBuf,
+    /// run a maintenance command instead of the bot
+    #[argh(subcommand)]
+    command: Option<cli::Command>,
 }
 
 /// The struct containing the bot configuration
 #[derive(Serialize, Deserialize, Clone)]
 struct Config {
     history_db: String,
+    history_key: Option<String>,
+    #[serde(default)]
+    old_history_keys: Vec<String>,
+    auto_resolve_hours: Option<u64>,
+    /// how many inbound messages are responded to at once
+    max_concurrent_jobs: Option<usize>,
     llm_config: LlmConfig,
     telegram_config: Option<TelegramConfig>,
     email_config: Option<EmailConfig>,
@@ -38,6 +58,19 @@ struct LlmConfig {
     openai_key: String,
     main_model: String,
     fallback_model: Option<String>,
+    /// model that reads images users send, if main_model cannot
+    vision_model: Option<String>,
+    /// speech-to-text for voice messages, which are ignored without it
+    transcription: Option<TranscriptionConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TranscriptionConfig {
+    /// a Whisper-compatible transcriptions endpoint
+    url: String,
+    /// defaults to the openai_key
+    api_key: Option<String>,
+    model: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -45,6 +78,18 @@ struct TelegramConfig {
     telegram_token: String,
     admin_uname: String,
     bot_uname: String,
+    /// the chat where the admin approves or rejects actions
+    admin_chat_id: Option<i64>,
+    /// receive updates through a webhook instead of polling for them
+    webhook: Option<TelegramWebhookConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TelegramWebhookConfig {
+    /// the public URL Telegram sends updates to, ending in /support-bot-telegram
+    url: String,
+    /// sent by Telegram with every update, to tell its requests apart from anyone else's
+    secret_token: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -59,6 +104,31 @@ struct EmailConfig {
 #[derive(Serialize, Deserialize, Clone)]
 struct ActionsConfig {
     binder_db: String,
+    confirmation_timeout_secs: Option<i64>,
+    /// how many actions the model can chain before it has to reply to the user
+    max_action_steps: Option<usize>,
+    #[serde(default)]
+    actions: HashMap<String, ActionSettings>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct ActionSettings {
+    #[serde(default = "default_true")]
+    enabled: bool,
+    /// only validate and log what the action would do, and tell the user a human will review it
+    #[serde(default)]
+    dry_run: bool,
+    /// never run the action before the admin approves it on Telegram
+    #[serde(default)]
+    requires_approval: bool,
+    /// how long one requester has to wait between two attempts at the action
+    cooldown_secs: Option<i64>,
+    /// how many attempts at the action everyone together can make in an hour
+    max_per_hour: Option<i64>,
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -77,18 +147,46 @@ static CONFIG: Lazy<Config> = Lazy::new(|| {
 });
 
 static DB: Lazy<ChatHistoryDb> = Lazy::new(|| {
-    smol::future::block_on(ChatHistoryDb::new(&CONFIG.history_db))
+    let cipher = HistoryCipher::from_config().expect("cannot load history encryption key");
+    smol::future::block_on(ChatHistoryDb::new(&CONFIG.history_db, cipher))
         .expect("cannot create chat history db")
 });
 
 fn main() {
     env_logger::init();
 
-    if CONFIG.email_config.is_some() {
-        smolscale::spawn(handle_email()).detach();
+    if let Some(command) = &ARGS.command {
+        if let Err(err) = smolscale::block_on(cli::run_command(command)) {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
     }
 
-    if CONFIG.telegram_config.is_some() {
-        smolscale::block_on(handle_telegram());
+    if CONFIG.actions_config.is_some() {
+        log::info!("enabled actions: {:?}", ACTIONS.names());
     }
+
+    if let Some(hours) = CONFIG.auto_resolve_hours {
+        smolscale::spawn(auto_resolve_loop(hours)).detach();
+    }
+
+    let telegram_webhook = CONFIG
+        .telegram_config
+        .as_ref()
+        .is_some_and(|config| config.webhook.is_some());
+    if CONFIG.email_config.is_some() || telegram_webhook {
+        smolscale::spawn(serve()).detach();
+    }
+
+    if telegram_webhook {
+        if let Err(err) = smolscale::block_on(set_webhook()) {
+            log::error!("{:?}", err);
+            std::process::exit(1);
+        }
+    } else if CONFIG.telegram_config.is_some() {
+        smolscale::spawn(handle_telegram()).detach();
+    }
+
+    smolscale::block_on(run_worker());
 }
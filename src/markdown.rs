@@ -0,0 +1,263 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+
+/// Converts the model's markdown into the subset of HTML that Telegram understands, escaping
+/// everything else
+pub fn to_telegram_html(markdown: &str) -> String {
+    let mut html = String::new();
+    // the next number of each ordered list we are in, or None for bulleted lists
+    let mut lists: Vec<Option<u64>> = vec![];
+    for event in Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH) {
+        match event {
+            Event::Start(tag) => match tag {
+                Tag::Heading(..) | Tag::Strong => html += "<b>",
+                Tag::Emphasis => html += "<i>",
+                Tag::Strikethrough => html += "<s>",
+                Tag::BlockQuote => html += "<blockquote>",
+                Tag::CodeBlock(CodeBlockKind::Fenced(lang)) if !lang.is_empty() => {
+                    html += &format!("<pre><code class=\"language-{}\">", escape(&lang))
+                }
+                Tag::CodeBlock(_) => html += "<pre><code>",
+                Tag::Link(_, url, _) | Tag::Image(_, url, _) => {
+                    html += &format!("<a href=\"{}\">", escape(&url))
+                }
+                Tag::List(start) => {
+                    // a nested list starts on its own line
+                    if !lists.is_empty() && !html.ends_with('\n') {
+                        html.push('\n');
+                    }
+                    lists.push(start)
+                }
+                Tag::Item => {
+                    html += &"  ".repeat(lists.len().saturating_sub(1));
+                    match lists.last_mut() {
+                        Some(Some(number)) => {
+                            html += &format!("{number}. ");
+                            *number += 1;
+                        }
+                        _ => html += "• ",
+                    }
+                }
+                _ => {}
+            },
+            Event::End(tag) => match tag {
+                Tag::Paragraph => html += "\n\n",
+                Tag::Heading(..) => html += "</b>\n\n",
+                Tag::Strong => html += "</b>",
+                Tag::Emphasis => html += "</i>",
+                Tag::Strikethrough => html += "</s>",
+                Tag::BlockQuote => {
+                    trim_newlines(&mut html);
+                    html += "</blockquote>\n\n";
+                }
+                Tag::CodeBlock(_) => {
+                    trim_newlines(&mut html);
+                    html += "</code></pre>\n\n";
+                }
+                Tag::Link(..) | Tag::Image(..) => html += "</a>",
+                Tag::List(_) => {
+                    lists.pop();
+                    if lists.is_empty() {
+                        html.push('\n');
+                    }
+                }
+                Tag::Item => {
+                    trim_newlines(&mut html);
+                    html.push('\n');
+                }
+                _ => {}
+            },
+            Event::Text(text) | Event::Html(text) => html += &escape(&text),
+            Event::Code(code) => html += &format!("<code>{}</code>", escape(&code)),
+            Event::SoftBreak | Event::HardBreak => html.push('\n'),
+            Event::Rule => html += "——————\n\n",
+            Event::TaskListMarker(done) => html += if done { "☑ " } else { "☐ " },
+            Event::FootnoteReference(name) => html += &format!("[{}]", escape(&name)),
+        }
+    }
+    html.trim_end().to_owned()
+}
+
+/// Splits a text into pieces of at most `max_chars` characters, between paragraphs where
+/// possible, then between lines, and mid-line only as a last resort. Code blocks are only split if
+/// they are too long on their own, and are then closed at the end of each piece and opened again
+/// at the start of the next, so that every piece still shows as code.
+pub fn split_message(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for paragraph in paragraphs(text) {
+        let separator = if current.is_empty() { "" } else { "\n\n" };
+        if current.chars().count() + separator.len() + paragraph.chars().count() <= max_chars {
+            current += separator;
+            current += &paragraph;
+            continue;
+        }
+        if !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if paragraph.chars().count() <= max_chars {
+            current = paragraph;
+            continue;
+        }
+        // the opening line of the code block we are in, if any
+        let mut fence: Option<&str> = None;
+        for line in paragraph.lines() {
+            let next_fence = match fence {
+                _ if !line.trim_start().starts_with(CODE_FENCE) => fence,
+                Some(_) => None,
+                None => Some(line),
+            };
+            // room to close the code block if the piece ends inside it
+            let closing = if fence.is_some() || next_fence.is_some() {
+                CODE_FENCE.len() + 1
+            } else {
+                0
+            };
+            let separator = if current.is_empty() { "" } else { "\n" };
+            let has_content = !current.is_empty() && Some(current.as_str()) != fence;
+            if has_content
+                && current.chars().count() + separator.len() + line.chars().count() + closing
+                    > max_chars
+            {
+                end_piece(&mut chunks, &mut current, fence);
+            }
+            // a line too long for a piece of its own is split mid-line
+            let mut rest: Vec<char> = line.chars().collect();
+            loop {
+                let separator = if current.is_empty() { "" } else { "\n" };
+                let room = max_chars
+                    .saturating_sub(current.chars().count() + separator.len() + closing)
+                    .max(1);
+                let tail = rest.split_off(room.min(rest.len()));
+                current += separator;
+                current.extend(rest);
+                rest = tail;
+                if rest.is_empty() {
+                    break;
+                }
+                end_piece(&mut chunks, &mut current, fence);
+            }
+            fence = next_fence;
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+const CODE_FENCE: &str = "```";
+
+/// Ends the current piece, closing the code block it ends in and opening it again for the next
+fn end_piece(chunks: &mut Vec<String>, current: &mut String, fence: Option<&str>) {
+    if fence.is_some() {
+        *current += "\n";
+        *current += CODE_FENCE;
+    }
+    chunks.push(std::mem::take(current));
+    if let Some(fence) = fence {
+        *current = fence.to_owned();
+    }
+}
+
+/// The paragraphs of a text, keeping fenced code blocks with blank lines in one piece
+fn paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs: Vec<String> = vec![];
+    let mut in_code_block = false;
+    for paragraph in text.split("\n\n") {
+        match paragraphs.last_mut() {
+            Some(last) if in_code_block => {
+                *last += "\n\n";
+                *last += paragraph;
+            }
+            _ => paragraphs.push(paragraph.to_owned()),
+        }
+        if paragraph.matches("```").count() % 2 == 1 {
+            in_code_block = !in_code_block;
+        }
+    }
+    paragraphs
+        .into_iter()
+        .map(|paragraph| paragraph.trim_matches('\n').to_owned())
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect()
+}
+
+fn trim_newlines(html: &mut String) {
+    html.truncate(html.trim_end_matches('\n').len());
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_lists() {
+        let html = to_telegram_html("1. first\n   - inner\n   - other\n2. second");
+        assert_eq!(html, "1. first\n  • inner\n  • other\n2. second");
+    }
+
+    #[test]
+    fn escapes_html() {
+        let html = to_telegram_html("a <b> & \"c\" `x < y` [link](https://e.com/?a=1&b=\"2\")");
+        assert_eq!(
+            html,
+            "a &lt;b&gt; &amp; &quot;c&quot; <code>x &lt; y</code> \
+            <a href=\"https://e.com/?a=1&amp;b=&quot;2&quot;\">link</a>"
+        );
+    }
+
+    #[test]
+    fn code_blocks() {
+        let html = to_telegram_html("```rust\nlet a = 1 < 2;\n```");
+        assert_eq!(
+            html,
+            "<pre><code class=\"language-rust\">let a = 1 &lt; 2;</code></pre>"
+        );
+    }
+
+    #[test]
+    fn splits_between_paragraphs() {
+        let chunks = split_message("one\n\ntwo\n\nthree", 8);
+        assert_eq!(chunks, ["one\n\ntwo", "three"]);
+    }
+
+    #[test]
+    fn splits_multi_byte_characters() {
+        let text = "ü".repeat(5) + "😀😀😀";
+        let chunks = split_message(&text, 3);
+        assert_eq!(chunks, ["üüü", "üü😀", "😀😀"]);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 3));
+    }
+
+    #[test]
+    fn keeps_short_code_blocks_whole() {
+        let text = "intro\n\n```\na\n\nb\n```";
+        assert_eq!(split_message(text, 12), ["intro", "```\na\n\nb\n```"]);
+    }
+
+    #[test]
+    fn reopens_long_code_blocks() {
+        let code: Vec<String> = (0..6).map(|i| format!("line {i}")).collect();
+        let text = format!("```rust\n{}\n```", code.join("\n"));
+        let chunks = split_message(&text, 30);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 30, "{chunk:?} is too long");
+            assert!(chunk.starts_with("```rust\n"), "{chunk:?} does not open");
+            assert!(chunk.ends_with("\n```"), "{chunk:?} does not close");
+        }
+        let lines: Vec<&str> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.lines())
+            .filter(|line| !line.starts_with("```"))
+            .collect();
+        assert_eq!(lines, code);
+    }
+}
@@ -1,8 +1,12 @@
+use std::collections::HashSet;
+
 use anyhow::Context;
+use async_compat::CompatExt;
+use base64::Engine;
 use isahc::{AsyncReadResponseExt, Request, RequestExt};
 use serde_json::{json, Value};
 
-use crate::{actions::ACTIONS_PROMPT, CONFIG, DB};
+use crate::{actions::ACTIONS, CONFIG, DB};
 
 pub async fn call_openai_api(
     model: &str,
@@ -20,8 +24,80 @@ pub async fn call_openai_api(
         "messages": msgs,
         "max_tokens": 500
     });
+    chat_completion(req).await
+}
+
+/// Asks the vision model what an image a user sent shows, taking the user's caption into account
+pub async fn describe_image(
+    image: &[u8],
+    mime_type: &str,
+    caption: &str,
+) -> anyhow::Result<String> {
+    let llm_config = &CONFIG.llm_config;
+    let model = llm_config
+        .vision_model
+        .as_ref()
+        .unwrap_or(&llm_config.main_model);
+    let image_url = format!(
+        "data:{mime_type};base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(image)
+    );
+    let req = json!({
+        "model": model,
+        "messages": [
+            {"role": "system", "content": include_str!("image-prompt.txt")},
+            {"role": "user", "content": [
+                {"type": "text", "text": caption},
+                {"type": "image_url", "image_url": {"url": image_url}},
+            ]},
+        ],
+        "max_tokens": 500
+    });
+    chat_completion(req).await
+}
+
+/// Transcribes a voice message through the configured Whisper-compatible endpoint. The file name's
+/// extension tells the endpoint what format the audio is in.
+pub async fn transcribe_audio(audio: Vec<u8>, file_name: &str) -> anyhow::Result<String> {
+    let llm_config = &CONFIG.llm_config;
+    let transcription = llm_config
+        .transcription
+        .as_ref()
+        .context("voice transcription is not configured")?;
+    let api_key = transcription
+        .api_key
+        .as_ref()
+        .unwrap_or(&llm_config.openai_key);
+    let form = reqwest::multipart::Form::new()
+        .text("model", transcription.model.clone())
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(audio).file_name(file_name.to_owned()),
+        );
+    let resp = reqwest::Client::new()
+        .post(&transcription.url)
+        .bearer_auth(api_key)
+        .multipart(form)
+        .send()
+        .compat()
+        .await?;
+    let status = resp.status();
+    let resp = resp.bytes().compat().await?;
+    if !status.is_success() {
+        return Err(OpenAiError {
+            status: status.as_u16(),
+            body: String::from_utf8_lossy(&resp).into_owned(),
+        }
+        .into());
+    }
+    let resp: Value = serde_json::from_slice(&resp)?;
+    let text = resp["text"].as_str().context("no text in transcription")?;
+    Ok(text.trim().to_owned())
+}
 
-    let mut resp: Value = Request::post("https://api.openai.com/v1/chat/completions")
+/// Sends a request to OpenAI's chat completions API and returns the reply's text
+async fn chat_completion(req: Value) -> anyhow::Result<String> {
+    let mut resp = Request::post("https://api.openai.com/v1/chat/completions")
         .header("Content-Type", "application/json")
         .header(
             "Authorization",
@@ -29,9 +105,15 @@ pub async fn call_openai_api(
         )
         .body(serde_json::to_vec(&req)?)?
         .send_async()
-        .await?
-        .json()
         .await?;
+    if !resp.status().is_success() {
+        return Err(OpenAiError {
+            status: resp.status().as_u16(),
+            body: resp.text().await?,
+        }
+        .into());
+    }
+    let mut resp: Value = resp.json().await?;
     log::debug!("OPENAI RESP = {:?}", resp);
     let resp = &mut resp["choices"][0]["message"];
     if resp["role"].is_string() {
@@ -45,12 +127,142 @@ pub async fn call_openai_api(
     }
 }
 
-pub async fn get_chatbot_prompt(actions_enabled: bool) -> anyhow::Result<String> {
+/// An error status returned by OpenAI, or by the transcription endpoint
+#[derive(Clone, Debug)]
+pub struct OpenAiError {
+    pub status: u16,
+    /// the response, which explains the error
+    pub body: String,
+}
+
+impl OpenAiError {
+    /// Whether sending the same request again would fail the same way, e.g. because the input
+    /// was rejected. Rate limits and timeouts pass.
+    pub fn is_permanent(&self) -> bool {
+        (400..500).contains(&self.status) && self.status != 408 && self.status != 429
+    }
+}
+
+impl std::fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "openai failed with status {}: {}",
+            self.status, self.body
+        )
+    }
+}
+
+impl std::error::Error for OpenAiError {}
+
+/// How many learned facts go into a prompt at most
+const MAX_PROMPT_FACTS: usize = 10;
+
+/// Builds the system prompt. Given the conversation, only the learned facts relevant to it go in;
+/// otherwise all of them do. Returns the prompt and the ids of the facts in it.
+pub async fn get_chatbot_prompt(
+    actions_enabled: bool,
+    convo: Option<&str>,
+) -> anyhow::Result<(String, Vec<i64>)> {
     let mut initial_prompt = include_str!("initial-prompt.txt").to_owned();
     if actions_enabled {
-        initial_prompt = initial_prompt + ACTIONS_PROMPT;
+        initial_prompt += &ACTIONS.prompt();
+    }
+    let mut facts = DB.get_all_facts().await?;
+    if let Some(convo) = convo {
+        facts = relevant_facts(facts, convo);
+    }
+    let (fact_ids, facts): (Vec<i64>, Vec<String>) = facts.into_iter().unzip();
+    let ret = initial_prompt + "\n" + &facts.join("\n");
+    Ok((ret, fact_ids))
+}
+
+/// Picks the facts sharing the most words with the conversation, leaving out those sharing none
+fn relevant_facts(facts: Vec<(i64, String)>, convo: &str) -> Vec<(i64, String)> {
+    let convo_words = significant_words(convo);
+    let mut scored: Vec<(usize, (i64, String))> = facts
+        .into_iter()
+        .map(|fact| {
+            let shared = significant_words(&fact.1)
+                .intersection(&convo_words)
+                .count();
+            (shared, fact)
+        })
+        .filter(|(shared, _)| *shared > 0)
+        .collect();
+    // most shared words first, then in the order they were learned
+    scored.sort_by(|(a, fact_a), (b, fact_b)| b.cmp(a).then(fact_a.0.cmp(&fact_b.0)));
+    scored
+        .into_iter()
+        .take(MAX_PROMPT_FACTS)
+        .map(|(_, fact)| fact)
+        .collect()
+}
+
+/// Words too common to tell what a text is about
+const STOP_WORDS: &[&str] = &[
+    "about", "could", "does", "from", "have", "that", "their", "there", "they", "this", "what",
+    "when", "which", "will", "with", "would", "your",
+];
+
+/// The lowercased words of a text that tell what it is about
+fn significant_words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 4)
+        .map(|word| word.to_lowercase())
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn facts(facts: &[&str]) -> Vec<(i64, String)> {
+        facts
+            .iter()
+            .enumerate()
+            .map(|(i, fact)| (i as i64 + 1, fact.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn picks_facts_sharing_words() {
+        let picked = relevant_facts(
+            facts(&[
+                "Geph land sky color is pink",
+                "Plus can be paid for with Alipay",
+                "Refunds take five days to process",
+            ]),
+            "Can I pay for Plus with alipay?",
+        );
+        assert_eq!(picked.iter().map(|f| f.0).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn ranks_by_shared_words() {
+        let picked = relevant_facts(
+            facts(&[
+                "Plus renews monthly",
+                "Plus renews monthly unless cancelled",
+            ]),
+            "my plus renews monthly, how do I get it cancelled?",
+        );
+        assert_eq!(picked.iter().map(|f| f.0).collect::<Vec<_>>(), [2, 1]);
+    }
+
+    #[test]
+    fn ignores_short_and_common_words() {
+        assert!(relevant_facts(facts(&["It is on the way"]), "is it on?").is_empty());
+        assert!(relevant_facts(facts(&["Pay with Alipay"]), "what about with cards").is_empty());
+    }
+
+    #[test]
+    fn caps_the_facts() {
+        let many: Vec<String> = (0..20).map(|i| format!("servers {i}")).collect();
+        let many: Vec<&str> = many.iter().map(|s| s.as_str()).collect();
+        let picked = relevant_facts(facts(&many), "servers down");
+        assert_eq!(picked.len(), MAX_PROMPT_FACTS);
+        assert_eq!(picked[0].0, 1);
     }
-    let facts = DB.get_all_facts().await?.join("\n");
-    let ret = initial_prompt + "\n" + &facts;
-    Ok(ret)
 }
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use serde_json::Value;
+use smol::lock::Semaphore;
+use smol_timeout::TimeoutExt;
+
+use crate::{
+    database::Job, email::process_email, responder::process_notification, telegram::process_update,
+    CONFIG, DB,
+};
+
+/// How many times a job is tried before it is declared dead
+const MAX_ATTEMPTS: i64 = 5;
+
+/// How many jobs are processed at once, unless configured otherwise
+const DEFAULT_MAX_CONCURRENT_JOBS: usize = 8;
+
+/// How long the worker waits before checking an empty queue again
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a job may run before it is given up on and retried, so that a hung call does not hold
+/// its slot and its chat forever
+const JOB_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The kinds of inbound messages that go through the queue, and of messages the bot sends on its
+/// own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobKind {
+    TelegramUpdate,
+    Email,
+    Notification,
+}
+
+impl std::fmt::Display for JobKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobKind::TelegramUpdate => write!(f, "telegram_update"),
+            JobKind::Email => write!(f, "email"),
+            JobKind::Notification => write!(f, "notification"),
+        }
+    }
+}
+
+impl std::str::FromStr for JobKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "telegram_update" => Ok(JobKind::TelegramUpdate),
+            "email" => Ok(JobKind::Email),
+            "notification" => Ok(JobKind::Notification),
+            _ => anyhow::bail!("unknown job kind {s}"),
+        }
+    }
+}
+
+/// Durably stores a message for the worker to process
+pub async fn enqueue_job(kind: JobKind, payload: &Value) -> anyhow::Result<()> {
+    let job_id = DB
+        .insert_job(
+            &kind.to_string(),
+            &payload.to_string(),
+            ordering_key(kind, payload).as_deref(),
+        )
+        .await?;
+    log::debug!("queued {kind} job {job_id}");
+    Ok(())
+}
+
+/// Jobs from the same Telegram chat or email sender, and notifications to them, are processed in
+/// the order they came in
+fn ordering_key(kind: JobKind, payload: &Value) -> Option<String> {
+    match kind {
+        JobKind::TelegramUpdate => [
+            &payload["message"],
+            &payload["edited_message"],
+            &payload["callback_query"]["message"],
+        ]
+        .iter()
+        .find_map(|msg| msg["chat"]["id"].as_i64())
+        .map(|chat_id| format!("telegram:{chat_id}")),
+        JobKind::Email => payload["from"]
+            .as_str()
+            .map(|sender| format!("email:{sender}")),
+        JobKind::Notification => {
+            let requester = &payload["requester"];
+            match requester["reply_to"]["Telegram"]["chat_id"].as_i64() {
+                Some(chat_id) => Some(format!("telegram:{chat_id}")),
+                None => requester["id"].as_str().map(|id| format!("email:{id}")),
+            }
+        }
+    }
+}
+
+/// Processes queued jobs forever, several at a time, starting with the ones interrupted by the
+/// last shutdown
+pub async fn run_worker() {
+    match DB.requeue_running_jobs().await {
+        Ok(0) => {}
+        Ok(count) => log::info!("resuming {count} unfinished jobs"),
+        Err(err) => log::error!("cannot resume unfinished jobs: {:?}", err),
+    }
+    let slots = Arc::new(Semaphore::new(
+        CONFIG
+            .max_concurrent_jobs
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_JOBS),
+    ));
+    loop {
+        let slot = slots.acquire_arc().await;
+        let job = match DB.claim_job().await {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                drop(slot);
+                smol::Timer::after(POLL_INTERVAL).await;
+                continue;
+            }
+            Err(err) => {
+                drop(slot);
+                log::error!("cannot take a job from the queue: {:?}", err);
+                smol::Timer::after(POLL_INTERVAL).await;
+                continue;
+            }
+        };
+        smolscale::spawn(async move {
+            handle_job(job).await;
+            drop(slot);
+        })
+        .detach();
+    }
+}
+
+/// Runs a job, then marks it as done, or as failed to be retried later. Jobs save their reply before
+/// sending it, so a retry sends the rest of it instead of responding again.
+async fn handle_job(job: Job) {
+    let result = run_job(&job).timeout(JOB_TIMEOUT).await.unwrap_or_else(|| {
+        Err(anyhow::anyhow!(
+            "timed out after {}s",
+            JOB_TIMEOUT.as_secs()
+        ))
+    });
+    let res = match result {
+        Ok(()) => DB.finish_job(job.job_id).await,
+        Err(err) => {
+            let retry_after =
+                (job.attempts < MAX_ATTEMPTS).then(|| 30 * 2i64.pow(job.attempts as u32 - 1));
+            match retry_after {
+                Some(secs) => {
+                    log::warn!("job {} failed, retrying in {secs}s: {:?}", job.job_id, err)
+                }
+                None => log::error!(
+                    "job {} failed {} times, giving up: {:?}",
+                    job.job_id,
+                    job.attempts,
+                    err
+                ),
+            }
+            DB.fail_job(job.job_id, &format!("{:?}", err), retry_after)
+                .await
+        }
+    };
+    if let Err(err) = res {
+        log::error!("cannot update job {}: {:?}", job.job_id, err);
+    }
+}
+
+async fn run_job(job: &Job) -> anyhow::Result<()> {
+    match job.kind.parse()? {
+        JobKind::TelegramUpdate => process_update(job, serde_json::from_str(&job.payload)?).await,
+        JobKind::Email => {
+            let email: HashMap<String, String> = serde_json::from_str(&job.payload)?;
+            process_email(job, email).await
+        }
+        JobKind::Notification => {
+            process_notification(job, serde_json::from_str(&job.payload)?).await
+        }
+    }
+}
@@ -1,57 +1,293 @@
 use std::time::Duration;
 
 use crate::{
-    actions::{transfer_plus, Action, AiResponse},
-    database::trim_convo_history,
+    actions::{
+        invoke, run_confirmed_action, ActionContext, AiResponse, Invocation, ABORT, ACTIONS,
+        NO_ACTION,
+    },
+    database::{trim_convo_history, ConvoStatus, Job, Platform, ReplyOrigin},
+    email,
     openai::{call_openai_api, get_chatbot_prompt},
-    Message, CONFIG, DB,
+    queue::{enqueue_job, JobKind},
+    telegram, Message, CONFIG, DB,
 };
 
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use smol::future::FutureExt;
 
-pub async fn respond(msg: Message) -> anyhow::Result<String> {
+/// Who the bot is responding to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Requester {
+    pub platform: Platform,
+    /// Telegram user id or email address
+    pub id: String,
+    /// Telegram username or email sender name, for humans reading logs
+    pub name: String,
+    /// where to reach them later, e.g. once the admin decides on an action they asked for
+    pub reply_to: ReplyTarget,
+}
+
+/// The message a later notification should answer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ReplyTarget {
+    Telegram {
+        chat_id: i64,
+        message_id: i64,
+        /// the forum topic the message was sent in
+        #[serde(default)]
+        thread_id: Option<i64>,
+    },
+    /// the email address is the requester's id
+    Email { subject: String, message_id: String },
+}
+
+/// A message to a requester outside of replying to them
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub requester: Requester,
+    pub convo_id: i64,
+    pub text: String,
+}
+
+/// Queues a message to a requester outside of replying to them, to be sent on their original
+/// platform and added to the conversation. It is sent by a job of its own, so that it is retried on
+/// its own if sending fails.
+pub async fn notify(requester: &Requester, convo_id: i64, text: &str) -> anyhow::Result<()> {
+    let notification = Notification {
+        requester: requester.clone(),
+        convo_id,
+        text: text.to_owned(),
+    };
+    enqueue_job(JobKind::Notification, &serde_json::to_value(notification)?).await
+}
+
+/// Sends a queued notification
+pub async fn process_notification(job: &Job, notification: Notification) -> anyhow::Result<()> {
+    let Notification {
+        requester,
+        convo_id,
+        text,
+    } = notification;
+    match &requester.reply_to {
+        ReplyTarget::Telegram {
+            chat_id,
+            message_id,
+            thread_id,
+        } => telegram::send_followup(job, convo_id, *chat_id, *message_id, *thread_id, &text).await,
+        ReplyTarget::Email {
+            subject,
+            message_id,
+        } => email::send_followup(job, convo_id, &requester.id, subject, message_id, &text).await,
+    }
+}
+
+/// The bot's reply to a message
+pub struct Reply {
+    /// empty if the bot should not reply
+    pub text: String,
+    /// the model that decided on the reply, if one did
+    pub model: Option<String>,
+    /// the learned facts the model was given
+    pub facts_used: Vec<i64>,
+}
+
+impl Reply {
+    /// What wrote the reply, if a model did
+    pub fn origin(&self) -> Option<ReplyOrigin<'_>> {
+        self.model.as_deref().map(|model| ReplyOrigin {
+            model,
+            facts_used: &self.facts_used,
+        })
+    }
+}
+
+/// How many actions the model can perform in a row before it has to reply, unless configured
+/// otherwise
+const DEFAULT_MAX_ACTION_STEPS: usize = 3;
+
+/// What the user is told when the model still does not reply once it cannot perform more actions
+const STEP_LIMIT_REPLY: &str = "Sorry, I could not finish handling your request. Please try again in a little while, or ask for a human if you need one.";
+
+pub async fn respond(msg: Message, requester: &Requester) -> anyhow::Result<Reply> {
     let actions_enabled = CONFIG.actions_config.is_some();
-    let llm_config = CONFIG.llm_config.clone();
 
-    // prompt
-    let prompt = get_chatbot_prompt(actions_enabled).await?;
+    DB.ensure_convo(msg.convo_id, requester.platform).await?;
+    // a resolved conversation picks up again where it left off
+    let status = DB.get_convo_status(msg.convo_id).await?;
+    if status == Some(ConvoStatus::Resolved) {
+        log::info!("reopening resolved convo {}", msg.convo_id);
+        DB.set_convo_status(msg.convo_id, ConvoStatus::Open).await?;
+    }
+
+    // this message may be the go-ahead for an action the bot asked about last time
+    let confirmed_note = if actions_enabled {
+        run_confirmed_action(requester, msg.convo_id, &msg.text).await?
+    } else {
+        None
+    };
+
     // chat history
     let mut role_contents = trim_convo_history(DB.get_convo_history(msg.convo_id).await?).await;
-    let latest_msg = ("user".to_owned(), msg.text);
+    let latest_msg = ("user".to_owned(), msg.text.clone());
     role_contents.push(latest_msg);
+    // prompt, with the facts that bear on what the user said
+    let user_text = role_contents
+        .iter()
+        .filter(|(role, _)| role == "user")
+        .map(|(_, content)| content.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (prompt, facts_used) = get_chatbot_prompt(actions_enabled, Some(&user_text)).await?;
+    if let Some(note) = confirmed_note {
+        role_contents.push(("system".to_owned(), note));
+    }
+
+    let mut last_model: Option<String>;
+    let text = if actions_enabled {
+        let max_steps = CONFIG
+            .actions_config
+            .as_ref()
+            .unwrap()
+            .max_action_steps
+            .unwrap_or(DEFAULT_MAX_ACTION_STEPS);
+        let mut steps = 0;
+        loop {
+            let (model, resp_string) = ask_model(&prompt, &role_contents).await?;
+            last_model = Some(model.clone());
+            let resp = parse_response(&resp_string);
+            let name = resp.action.as_str();
+            let handler = match name {
+                NO_ACTION => break resp.text,
+                ABORT => {
+                    return Ok(Reply {
+                        text: "".to_string(),
+                        model: last_model,
+                        facts_used,
+                    })
+                }
+                name => match ACTIONS.get(name) {
+                    Some(handler) => handler,
+                    None => {
+                        log::warn!("model asked for unknown action {name}");
+                        break resp.text;
+                    }
+                },
+            };
+            role_contents.push(("assistant".to_owned(), resp_string.clone()));
+            if steps == max_steps {
+                log::warn!(
+                    "convo {} hit the limit of {max_steps} actions per reply",
+                    msg.convo_id
+                );
+                role_contents.push((
+                    "system".to_owned(),
+                    format!("The {name} action was not performed: no more actions can be performed now. Reply to the user with the \"{NO_ACTION}\" action."),
+                ));
+                let (model, resp_string) = ask_model(&prompt, &role_contents).await?;
+                last_model = Some(model);
+                let resp = parse_response(&resp_string);
+                if resp.action == NO_ACTION && !resp.text.is_empty() {
+                    break resp.text;
+                }
+                // the user gets an answer even if the model insists on acting
+                log::warn!(
+                    "model did not reply after the action limit in convo {}",
+                    msg.convo_id
+                );
+                break STEP_LIMIT_REPLY.to_owned();
+            }
+            steps += 1;
 
-    let resp_string = match llm_config.fallback_model {
+            let ctx = ActionContext {
+                convo_id: msg.convo_id,
+                requester: requester.clone(),
+                model: Some(model),
+                user_text: msg.text.clone(),
+            };
+            // the model writes its reply, or picks another action, once it knows how this one went
+            let result = match invoke(&ctx, handler, resp.args).await {
+                Ok(Invocation::Performed(outcome)) => json!({
+                    "action": name,
+                    "success": outcome.success,
+                    "result": outcome.message,
+                }),
+                Ok(Invocation::NeedsConfirmation(question)) => break question,
+                Ok(Invocation::NeedsApproval(reply)) => break reply,
+                Err(err) => {
+                    log::error!("action {name} failed: {:?}", err);
+                    json!({"action": name, "success": false, "error": err.to_string()})
+                }
+            };
+            role_contents.push((
+                "system".to_owned(),
+                format!("Action result: {result}\nReply to the user based on this result, or perform another action if one is needed."),
+            ));
+        }
+    } else {
+        let (model, text) = ask_model(&prompt, &role_contents).await?;
+        last_model = Some(model);
+        text
+    };
+
+    // the bot has answered, so the ball is in the user's court unless a human was asked to step in
+    if DB.get_convo_status(msg.convo_id).await? != Some(ConvoStatus::Escalated) {
+        DB.set_convo_status(msg.convo_id, ConvoStatus::WaitingOnUser)
+            .await?;
+    }
+    Ok(Reply {
+        text,
+        model: last_model,
+        facts_used,
+    })
+}
+
+/// Asks the main model for a reply, falling back to the fallback model if the main one takes too
+/// long. Returns the model that replied, and its reply.
+async fn ask_model(
+    prompt: &str,
+    role_contents: &[(String, String)],
+) -> anyhow::Result<(String, String)> {
+    let llm_config = &CONFIG.llm_config;
+    let main_model = async {
+        let resp = call_openai_api(&llm_config.main_model, prompt, role_contents).await?;
+        anyhow::Ok((llm_config.main_model.clone(), resp))
+    };
+    match &llm_config.fallback_model {
         Some(fallback_model) => {
-            call_openai_api(&llm_config.main_model, &prompt, &role_contents)
+            main_model
                 .or(async {
                     smol::Timer::after(Duration::from_secs(500)).await;
                     log::warn!("FALLBACK to {}", fallback_model);
-                    call_openai_api(&fallback_model, &prompt, &role_contents).await
+                    let resp = call_openai_api(fallback_model, prompt, role_contents).await?;
+                    anyhow::Ok((fallback_model.clone(), resp))
                 })
-                .await?
+                .await
         }
-        None => todo!(),
-    };
+        None => main_model.await,
+    }
+}
 
-    if actions_enabled {
-        let resp = serde_json::from_str(&resp_string).unwrap_or_else(|_| AiResponse {
-            action: Action::Null,
-            text: resp_string.clone(),
-        });
-        // perform the action
-        match resp.action {
-            Action::Null => {}
-            Action::TransferPlus {
-                old_uname,
-                new_uname,
-            } => {
-                transfer_plus(&old_uname, &new_uname).await?;
-            }
-            Action::Abort => return Ok("".to_string()),
-        };
+/// Reads the model's reply as an `AiResponse`, taking it as plain text if it is not one
+fn parse_response(resp_string: &str) -> AiResponse {
+    serde_json::from_str(resp_string).unwrap_or_else(|_| AiResponse {
+        action: NO_ACTION.to_owned(),
+        args: Value::Null,
+        text: resp_string.to_owned(),
+    })
+}
 
-        Ok(resp.text)
-    } else {
-        Ok(resp_string)
+/// Periodically resolves conversations that have been inactive for `auto_resolve_hours`
+pub async fn auto_resolve_loop(auto_resolve_hours: u64) {
+    loop {
+        match DB
+            .resolve_inactive_convos(auto_resolve_hours as i64 * 3600)
+            .await
+        {
+            Ok(0) => {}
+            Ok(count) => log::info!("auto-resolved {count} inactive convos"),
+            Err(err) => log::error!("cannot auto-resolve convos: {:?}", err),
+        }
+        smol::Timer::after(Duration::from_secs(600)).await;
     }
 }
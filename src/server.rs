@@ -0,0 +1,34 @@
+use async_compat::CompatExt;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use crate::{email::email_route, telegram::webhook_route, CONFIG};
+
+/// Serves the HTTP endpoints of the enabled platforms: Mailgun's email webhook, and Telegram's
+/// update webhook in webhook mode
+pub async fn serve() {
+    let mut routes: Vec<BoxedFilter<(Box<dyn Reply>,)>> = vec![];
+    if CONFIG.email_config.is_some() {
+        routes.push(boxed_reply(email_route()));
+    }
+    if CONFIG
+        .telegram_config
+        .as_ref()
+        .is_some_and(|config| config.webhook.is_some())
+    {
+        routes.push(boxed_reply(webhook_route()));
+    }
+    let Some(routes) = routes
+        .into_iter()
+        .reduce(|all, route| all.or(route).unify().boxed())
+    else {
+        return;
+    };
+    warp::serve(routes).run(([0, 0, 0, 0], 3030)).compat().await;
+}
+
+/// Erases the reply type of a route, so that routes with different replies can be combined
+fn boxed_reply<R: Reply + 'static>(route: BoxedFilter<(R,)>) -> BoxedFilter<(Box<dyn Reply>,)> {
+    route
+        .map(|reply: R| Box::new(reply) as Box<dyn Reply>)
+        .boxed()
+}
@@ -2,13 +2,21 @@ use std::time::Duration;
 
 use anyhow::Context;
 use isahc::{AsyncReadResponseExt, Request};
+use once_cell::sync::Lazy;
 use serde_json::{json, Value};
 use smol_timeout::TimeoutExt;
+use subtle::ConstantTimeEq;
+use warp::{filters::BoxedFilter, http::StatusCode, reply::WithStatus, Filter, Rejection};
 
 use crate::{
-    database::{Platform, Role},
+    actions::{decide_approval, truncate},
+    admin::{admin_command, is_admin_command},
+    database::{ConvoStatus, Job, Platform, ReplyProgress},
     learn::learn,
-    responder::respond,
+    markdown::{split_message, to_telegram_html},
+    openai::{describe_image, transcribe_audio, OpenAiError},
+    queue::{enqueue_job, JobKind},
+    responder::{respond, Reply, ReplyTarget, Requester},
     Message, CONFIG, DB,
 };
 
@@ -30,8 +38,44 @@ impl TelegramBot {
         }
     }
 
-    /// Calls a Telegram API.
-    pub async fn call_api(&self, method: &str, args: Value) -> anyhow::Result<Value> {
+    /// Calls a Telegram API. Calls hit by flood control are tried again once Telegram allows,
+    /// and calls to a group that became a supergroup are sent to the supergroup instead. Other
+    /// failures are returned as a `TelegramError` if Telegram explained them.
+    pub async fn call_api(&self, method: &str, mut args: Value) -> anyhow::Result<Value> {
+        let mut flood_retries = 0;
+        loop {
+            let err = match self.call_api_once(method, &args).await {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            let Some(tg_err) = err.downcast_ref::<TelegramError>() else {
+                return Err(err);
+            };
+            match (tg_err.retry_after, tg_err.migrate_to_chat_id) {
+                (Some(retry_after), _)
+                    if flood_retries < MAX_FLOOD_RETRIES && retry_after <= MAX_FLOOD_WAIT_SECS =>
+                {
+                    log::warn!("telegram flood control on {method}, retrying in {retry_after}s");
+                    flood_retries += 1;
+                    smol::Timer::after(Duration::from_secs(retry_after)).await;
+                }
+                (_, Some(new_chat_id)) if args["chat_id"].as_i64() != Some(new_chat_id) => {
+                    log::warn!(
+                        "chat {} became supergroup {new_chat_id}, sending {method} there",
+                        args["chat_id"]
+                    );
+                    args["chat_id"] = json!(new_chat_id);
+                    // the supergroup has its own message ids, so the message replied to is not there
+                    if !args["reply_to_message_id"].is_null() {
+                        args["allow_sending_without_reply"] = json!(true);
+                    }
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    async fn call_api_once(&self, method: &str, args: &Value) -> anyhow::Result<Value> {
         let raw_res: Value = self
             .client
             .send_async(
@@ -40,7 +84,7 @@ impl TelegramBot {
                     self.token
                 ))
                 .header("Content-Type", "application/json")
-                .body(serde_json::to_vec(&args)?)?,
+                .body(serde_json::to_vec(args)?)?,
             )
             .await?
             .json()
@@ -48,25 +92,112 @@ impl TelegramBot {
         if raw_res["ok"].as_bool().unwrap_or(false) {
             Ok(raw_res["result"].clone())
         } else {
-            anyhow::bail!(
-                "telegram failed with error code {}",
-                raw_res["error_code"]
+            Err(TelegramError {
+                error_code: raw_res["error_code"]
                     .as_i64()
-                    .context("could not parse error code as integer")?
+                    .context("could not parse error code as integer")?,
+                description: raw_res["description"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+                retry_after: raw_res["parameters"]["retry_after"].as_u64(),
+                migrate_to_chat_id: raw_res["parameters"]["migrate_to_chat_id"].as_i64(),
+            }
+            .into())
+        }
+    }
+
+    /// Downloads a file that a user sent to the bot.
+    pub async fn download_file(&self, file_id: &str) -> anyhow::Result<Vec<u8>> {
+        let file = self
+            .call_api("getFile", json!({ "file_id": file_id }))
+            .await?;
+        let file_path = file["file_path"]
+            .as_str()
+            .context("telegram gave no path for the file")?;
+        let mut resp = self
+            .client
+            .send_async(
+                Request::get(format!(
+                    "https://api.telegram.org/file/bot{}/{file_path}",
+                    self.token
+                ))
+                .body(())?,
             )
+            .await?;
+        if !resp.status().is_success() {
+            anyhow::bail!("telegram failed to send file with status {}", resp.status());
         }
+        Ok(resp.bytes().await?)
     }
 }
 
+/// An error returned by the Telegram bot API
+#[derive(Clone, Debug)]
+pub struct TelegramError {
+    pub error_code: i64,
+    pub description: String,
+    /// how many seconds to wait before calling again, when flood control kicked in
+    pub retry_after: Option<u64>,
+    /// the supergroup that a group chat was turned into
+    pub migrate_to_chat_id: Option<i64>,
+}
+
+impl std::fmt::Display for TelegramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "telegram failed with error code {}: {}",
+            self.error_code, self.description
+        )
+    }
+}
+
+impl std::error::Error for TelegramError {}
+
+/// How many times a call hit by flood control is tried again before giving up
+const MAX_FLOOD_RETRIES: u32 = 3;
+
+/// The longest flood control wait the bot sits out; for longer ones, the job queue retries the
+/// message later instead
+const MAX_FLOOD_WAIT_SECS: u64 = 60;
+
+/// How long one Telegram message can be, with some room to spare under Telegram's 4096 characters
+const MAX_MESSAGE_CHARS: usize = 4000;
+
+/// How long the answer to a pressed button can be. Telegram allows 200 characters, and the ellipsis
+/// of a truncated answer is one more.
+const MAX_CALLBACK_ANSWER_CHARS: usize = 199;
+
+/// How much of the result of a decision goes on its approval card, which also has to fit in one
+/// message
+const MAX_DECISION_CHARS: usize = 1000;
+
+/// How often the typing status is refreshed while a reply is being generated
+const TYPING_INTERVAL: Duration = Duration::from_secs(4);
+
+/// The bot's Telegram client
+pub static TELEGRAM: Lazy<TelegramBot> =
+    Lazy::new(|| TelegramBot::new(&CONFIG.telegram_config.as_ref().unwrap().telegram_token));
+
+/// Polls Telegram for updates and puts them into the job queue. An update is only
+/// acknowledged to Telegram, by moving the offset past it, once it is safely queued.
 pub async fn handle_telegram() {
-    let telegram = TelegramBot::new(&CONFIG.telegram_config.as_ref().unwrap().telegram_token);
-    let admin_uname = &CONFIG.telegram_config.as_ref().unwrap().admin_uname;
-    let bot_uname = &CONFIG.telegram_config.as_ref().unwrap().bot_uname;
-    let mut counter = 0;
+    // telegram refuses getUpdates while a webhook is set, e.g. after switching from webhook mode
+    if let Err(err) = TELEGRAM.call_api("deleteWebhook", json!({})).await {
+        log::error!("cannot delete telegram webhook: {:?}", err);
+    }
+    let mut counter = match DB.get_telegram_offset().await {
+        Ok(offset) => offset,
+        Err(err) => {
+            log::error!("cannot load telegram offset: {:?}", err);
+            0
+        }
+    };
     loop {
         log::info!("getting updates at {counter}");
         let fallible = async {
-            let updates = telegram
+            let updates = TELEGRAM
                 .call_api(
                     "getUpdates",
                     json!({"timeout": 120, "offset": counter + 1, "allowed_updates": []}),
@@ -75,80 +206,17 @@ pub async fn handle_telegram() {
                 .context("cannot call telegram for updates")?;
             let updates: Vec<Value> = serde_json::from_value(updates)?;
             for update in updates {
-                // we only support text msgs atm
+                enqueue_job(JobKind::TelegramUpdate, &update).await?;
                 counter = counter.max(update["update_id"].as_i64().unwrap_or_default());
-                if !update["message"]["text"].is_null() {
-                    let convo_id = get_convo_id(update.clone()).await?;
-                    let msg = update["message"]["text"]
-                        .as_str()
-                        .context("cannot parse out text")?;
-                    log::info!("msg = {msg}");
-                    if msg.contains(&("@".to_owned() + bot_uname))
-                        || update["message"]["reply_to_message"]["from"]["username"].as_str()
-                            == Some(bot_uname)
-                        || update["message"]["chat"]["type"].as_str() == Some("private")
-                    {
-                        let mut username = "";
-                        let mut message = Message {
-                            text: msg.replace(&("@".to_owned() + bot_uname), ""),
-                            convo_id,
-                        };
-                        if let Some(uname) = update["message"]["from"]["username"].as_str() {
-                            username = uname;
-                            message.text = uname.to_owned() + ": " + &message.text;
-                        };
-                        // learn if the chat is from the admin & contains "#learn"
-                        let resp = if username == admin_uname && message.text.contains("#learn") {
-                            learn(message.clone()).await?
-                        } else {
-                            respond(message.clone())
-                                .await
-                                .context("cannot calculate response")?
-                        };
-                        if resp != "".to_string() {
-                            // add question & response to db
-                            DB.insert_msg(
-                                &message,
-                                Platform::Telegram,
-                                Role::User,
-                                json!({"lol": "todo"}),
-                            )
-                            .await?;
-                            DB.insert_msg(
-                                &Message {
-                                    text: resp.clone(),
-                                    convo_id: message.convo_id,
-                                },
-                                Platform::Telegram,
-                                Role::Assistant,
-                                json!({"lol": "todo"}),
-                            )
-                            .await?;
-
-                            // send response to telegram
-                            let json_resp = telegram_json(
-                                resp,
-                                update["message"]["chat"]["id"]
-                                    .as_i64()
-                                    .context("could not get chat id")?,
-                                update["message"]["message_id"]
-                                    .as_i64()
-                                    .context("could not get message_id")?,
-                            );
-                            telegram
-                                .call_api("sendMessage", json_resp)
-                                .await
-                                .context("cannot send reply back to telegram")?;
-                        }
-                    }
-                }
             }
             anyhow::Ok(())
         };
         match fallible.timeout(Duration::from_secs(300)).await {
             Some(x) => {
                 if let Err(err) = x {
-                    log::error!("error getting updates: {:?}", err)
+                    log::error!("error getting updates: {:?}", err);
+                    // don't hammer telegram if it or the db is down
+                    smol::Timer::after(Duration::from_secs(5)).await;
                 }
             }
             None => log::error!("timed out getting telegram updates!"),
@@ -156,22 +224,855 @@ pub async fn handle_telegram() {
     }
 }
 
-async fn get_convo_id(update: Value) -> anyhow::Result<i64> {
-    if update["message"]["chat"]["type"] == "private" {
-        update["message"]["chat"]["id"]
-            .as_i64()
-            .context("chat id could not be converted to i64")
+/// The largest update the webhook accepts. Updates are small JSON objects; files are downloaded
+/// separately.
+const MAX_UPDATE_BYTES: u64 = 1024 * 1024;
+
+/// Receives updates from Telegram in webhook mode and puts them into the job queue. Requests
+/// without the configured secret token are turned away before their body is read.
+pub fn webhook_route() -> BoxedFilter<(WithStatus<&'static str>,)> {
+    let route = warp::path("support-bot-telegram").and(warp::post());
+    let accepted = route
+        .and(secret_token(true))
+        .and(warp::body::content_length_limit(MAX_UPDATE_BYTES))
+        .and(warp::body::json())
+        .then(|update: Value| async move {
+            match enqueue_job(JobKind::TelegramUpdate, &update).await {
+                Ok(()) => warp::reply::with_status("", StatusCode::OK),
+                Err(err) => {
+                    // telegram retries the update later
+                    log::error!("cannot queue telegram update: {:?}", err);
+                    warp::reply::with_status("", StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        });
+    let refused = route.and(secret_token(false)).map(|| {
+        log::warn!("telegram webhook called without the right secret token");
+        warp::reply::with_status("", StatusCode::UNAUTHORIZED)
+    });
+    accepted.or(refused).unify().boxed()
+}
+
+/// Passes requests that carry the configured secret token if `valid` is true, and the others if it
+/// is false. The token is compared in constant time.
+fn secret_token(valid: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("X-Telegram-Bot-Api-Secret-Token")
+        .and_then(move |token: Option<String>| async move {
+            let webhook = CONFIG
+                .telegram_config
+                .as_ref()
+                .and_then(|config| config.webhook.as_ref())
+                .unwrap();
+            let matches = token.is_some_and(|token| {
+                bool::from(token.as_bytes().ct_eq(webhook.secret_token.as_bytes()))
+            });
+            if matches == valid {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// How many times startup tries to register the webhook before giving up
+const SET_WEBHOOK_ATTEMPTS: u32 = 5;
+
+/// Tells Telegram to send updates to the configured webhook, retrying with backoff, since without
+/// it the bot would never hear of new messages
+pub async fn set_webhook() -> anyhow::Result<()> {
+    let webhook = CONFIG
+        .telegram_config
+        .as_ref()
+        .and_then(|config| config.webhook.as_ref())
+        .context("no telegram webhook configured")?;
+    let mut attempt = 1;
+    loop {
+        let result = TELEGRAM
+            .call_api(
+                "setWebhook",
+                json!({
+                    "url": webhook.url,
+                    "secret_token": webhook.secret_token,
+                    "allowed_updates": [],
+                }),
+            )
+            .await;
+        match result {
+            Ok(_) => break,
+            Err(err) if attempt < SET_WEBHOOK_ATTEMPTS => {
+                log::warn!(
+                    "cannot set telegram webhook (attempt {}): {:?}",
+                    attempt,
+                    err
+                );
+                smol::Timer::after(Duration::from_secs(5 << attempt)).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.context("cannot set telegram webhook")),
+        }
+    }
+    log::info!("telegram webhook set to {}", webhook.url);
+    Ok(())
+}
+
+/// Responds to a single Telegram update taken from the job queue, then moves the stored offset
+/// past it, so that polling picks up after it when the bot restarts
+pub async fn process_update(job: &Job, update: Value) -> anyhow::Result<()> {
+    handle_update(job, &update).await?;
+    if let Some(update_id) = update["update_id"].as_i64() {
+        DB.advance_telegram_offset(update_id).await?;
+    }
+    Ok(())
+}
+
+async fn handle_update(job: &Job, update: &Value) -> anyhow::Result<()> {
+    let admin_uname = &CONFIG.telegram_config.as_ref().unwrap().admin_uname;
+    let bot_uname = &CONFIG.telegram_config.as_ref().unwrap().bot_uname;
+    // the admin pressed a button on an approval card
+    if !update["callback_query"].is_null() {
+        return process_callback(job, &update["callback_query"]).await;
+    }
+    // an edited message is answered again, replacing the answer to what it said before
+    let (tg_msg, edited) = if !update["message"].is_null() {
+        (&update["message"], false)
+    } else if !update["edited_message"].is_null() {
+        (&update["edited_message"], true)
     } else {
-        if !update["message"]["reply_to_message"].is_null() {
-            if let Some(id) = DB
-                .txt_to_id(
-                    update["message"]["reply_to_message"]["text"]
-                        .as_str()
-                        .context("could not get reply_to_message text")?,
+        return Ok(());
+    };
+    // we support text msgs, and images and voice messages with or without a caption
+    let image = find_image(tg_msg);
+    let audio = find_audio(tg_msg);
+    let msg = match tg_msg["text"].as_str() {
+        Some(text) => text,
+        None if image.is_some() => tg_msg["caption"].as_str().unwrap_or_default(),
+        None if audio.is_some() && CONFIG.llm_config.transcription.is_some() => {
+            tg_msg["caption"].as_str().unwrap_or_default()
+        }
+        None => return Ok(()),
+    };
+    log::info!("msg = {msg}");
+    if !(msg.contains(&("@".to_owned() + bot_uname))
+        || tg_msg["reply_to_message"]["from"]["username"].as_str() == Some(bot_uname)
+        || tg_msg["chat"]["type"].as_str() == Some("private"))
+    {
+        return Ok(());
+    }
+    let chat_id = tg_msg["chat"]["id"]
+        .as_i64()
+        .context("could not get chat id")?;
+    let message_id = tg_msg["message_id"]
+        .as_i64()
+        .context("could not get message_id")?;
+    // in forum groups, the bot answers in the topic it was asked in
+    let thread_id = if tg_msg["is_topic_message"].as_bool() == Some(true) {
+        tg_msg["message_thread_id"].as_i64()
+    } else {
+        None
+    };
+    // the same update can come twice, e.g. when polling resumes after a restart. A retry of the
+    // job that reserved the message carries on with it.
+    let edit_date = tg_msg["edit_date"].as_i64().unwrap_or_default();
+    let handled = DB.get_telegram_reply(chat_id, message_id).await?;
+    if handled
+        .as_ref()
+        .is_some_and(|handled| handled.edit_date >= edit_date && handled.job_id != Some(job.job_id))
+    {
+        log::info!("already handled message {message_id} in chat {chat_id}");
+        return Ok(());
+    }
+    // an edit, or a retry, stays in the conversation of the message it changes
+    let convo_id = match handled.as_ref().and_then(|handled| handled.convo_id) {
+        Some(convo_id) => convo_id,
+        None => get_convo_id(tg_msg).await?,
+    };
+    let mut username = "";
+    let mut message = Message {
+        text: msg.replace(&("@".to_owned() + bot_uname), ""),
+        convo_id,
+    };
+    if edited {
+        message.text = format!("[edited their earlier message] {}", message.text);
+    }
+    if let Some(uname) = tg_msg["from"]["username"].as_str() {
+        username = uname;
+        message.text = uname.to_owned() + ": " + &message.text;
+    };
+    // admin commands are answered directly and kept out of the chat history
+    let is_command = username == admin_uname && is_admin_command(&message.text);
+    // running a command again because of a typo fix could do it twice
+    if is_command && edited {
+        log::info!("not running edited admin command {message_id} again");
+        return Ok(());
+    }
+    if !DB
+        .reserve_telegram_msg(
+            chat_id,
+            message_id,
+            edit_date,
+            (!is_command).then_some(convo_id),
+            job.job_id,
+        )
+        .await?
+    {
+        log::info!("message {message_id} in chat {chat_id} is handled by another job");
+        return Ok(());
+    }
+    // show that the bot is typing until the reply is sent, or handling the message fails
+    let _typing = (!is_command).then(|| keep_typing(chat_id, thread_id));
+    let mut progress = match job.progress.clone() {
+        // an earlier attempt already replied, but did not get all of the reply out
+        Some(progress) => progress,
+        None if is_command => {
+            let reply = admin_command(&message.text)
+                .await
+                .context("not an admin command")?
+                .unwrap_or_else(|err| format!("error: {:?}", err));
+            let progress = ReplyProgress {
+                text: reply,
+                ..Default::default()
+            };
+            DB.save_job_progress(job.job_id, &progress).await?;
+            progress
+        }
+        None => {
+            // the model reads a description of the image; the history keeps Telegram's id for the image
+            if let Some((file_id, mime_type, file_size)) = image {
+                match read_image(file_id, mime_type, file_size, msg).await? {
+                    Some(description) => {
+                        message.text +=
+                            &format!("\n[sent image {file_id}, which shows: {description}]")
+                    }
+                    None => {
+                        message.text +=
+                            &format!("\n[sent image {file_id}, which could not be read]")
+                    }
+                }
+            }
+            // a voice message is answered, and kept in the history, as its transcript
+            let mut untranscribed = false;
+            if let Some(audio) = audio {
+                match transcribe(&audio).await? {
+                    Some(transcript) => {
+                        message.text +=
+                            &format!("\n[transcribed from a voice message] {transcript}")
+                    }
+                    None => {
+                        message.text += "\n[sent a voice message, which could not be transcribed]";
+                        untranscribed = true;
+                    }
+                }
+            }
+            let caption = msg.replace(&("@".to_owned() + bot_uname), "");
+            // learn if the chat is from the admin & contains "#learn"
+            let reply = if username == admin_uname && message.text.contains("#learn") {
+                Reply {
+                    text: learn(message.clone()).await?,
+                    model: None,
+                    facts_used: vec![],
+                }
+            } else if untranscribed && caption.trim().is_empty() {
+                // there is nothing else to answer
+                Reply {
+                    text: UNTRANSCRIBED_REPLY.to_owned(),
+                    model: None,
+                    facts_used: vec![],
+                }
+            } else {
+                let requester = Requester {
+                    platform: Platform::Telegram,
+                    id: tg_msg["from"]["id"]
+                        .as_i64()
+                        .context("could not get sender id")?
+                        .to_string(),
+                    name: match username {
+                        "" => tg_msg["from"]["first_name"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_owned(),
+                        username => format!("@{username}"),
+                    },
+                    reply_to: ReplyTarget::Telegram {
+                        chat_id,
+                        message_id,
+                        thread_id,
+                    },
+                };
+                respond(message.clone(), &requester)
+                    .await
+                    .context("cannot calculate response")?
+            };
+            if reply.text.is_empty() {
+                ReplyProgress {
+                    in_convo: true,
+                    ..Default::default()
+                }
+            } else {
+                // add question & response to db
+                DB.store_reply(
+                    job.job_id,
+                    Some(&message),
+                    &Message {
+                        text: reply.text.clone(),
+                        convo_id,
+                    },
+                    Platform::Telegram,
+                    json!({"lol": "todo"}),
+                    reply.origin(),
                 )
+                .await?
+            }
+        }
+    };
+    // the messages of the answer to the message before it was edited
+    let old_reply_ids = handled
+        .map(|handled| handled.reply_message_ids)
+        .unwrap_or_default();
+    if progress.text.is_empty() {
+        // the earlier answer stays, since the bot has nothing to say to the edited message
+        DB.mark_telegram_msg_handled(
+            chat_id,
+            message_id,
+            edit_date,
+            Some(convo_id),
+            &old_reply_ids,
+        )
+        .await?;
+        return Ok(());
+    }
+    // send response to telegram, in place of the earlier answer if the message was edited
+    let feedback = progress.rated_msg_id.map(feedback_keyboard);
+    deliver(
+        job.job_id,
+        &mut progress,
+        chat_id,
+        &old_reply_ids,
+        message_id,
+        thread_id,
+        feedback,
+    )
+    .await
+    .context("cannot send reply back to telegram")?;
+    DB.mark_telegram_msg_handled(
+        chat_id,
+        message_id,
+        edit_date,
+        progress.in_convo.then_some(convo_id),
+        &progress.sent_ids,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Handles a button pressed on one of the bot's messages: an approval card, or the feedback
+/// buttons under a reply
+async fn process_callback(job: &Job, query: &Value) -> anyhow::Result<()> {
+    let query_id = query["id"]
+        .as_str()
+        .context("could not get callback query id")?;
+    let button = query["data"]
+        .as_str()
+        .and_then(|data| data.split_once(':'))
+        .and_then(|(verb, id)| Some((verb, id.parse::<i64>().ok()?)));
+    let answer = match button {
+        Some((verb @ ("approve" | "reject"), approval_id)) => {
+            process_decision(job, query, verb == "approve", approval_id).await?
+        }
+        Some((verb @ ("helpful" | "unhelpful" | "human"), msg_id)) => {
+            process_rating(query, verb, msg_id).await?
+        }
+        _ => "Unknown button.".to_owned(),
+    };
+    let answer = truncate(&answer, MAX_CALLBACK_ANSWER_CHARS);
+    // the answer only pops up for whoever pressed the button, and cannot be given anymore once the
+    // query is old, so it is not worth retrying the button for
+    if let Err(err) = TELEGRAM
+        .call_api(
+            "answerCallbackQuery",
+            json!({"callback_query_id": query_id, "text": answer}),
+        )
+        .await
+    {
+        log::warn!("cannot answer callback query {query_id}: {:?}", err);
+    }
+    Ok(())
+}
+
+/// Carries out the admin's decision on an approval card, and updates the card with the result
+async fn process_decision(
+    job: &Job,
+    query: &Value,
+    approved: bool,
+    approval_id: i64,
+) -> anyhow::Result<String> {
+    let admin_uname = &CONFIG.telegram_config.as_ref().unwrap().admin_uname;
+    let username = query["from"]["username"].as_str().unwrap_or_default();
+    if username != admin_uname {
+        return Ok("Only the admin can decide on actions.".to_owned());
+    }
+    let result = match job.progress.clone() {
+        // an earlier attempt carried out the decision, but did not get to update the card
+        Some(progress) => progress.text,
+        None => {
+            let result = decide_approval(approval_id, approved, &format!("@{username}"))
                 .await
-            {
-                return Ok(id);
+                .unwrap_or_else(|err| format!("error: {:?}", err));
+            let progress = ReplyProgress {
+                text: result.clone(),
+                ..Default::default()
+            };
+            DB.save_job_progress(job.job_id, &progress).await?;
+            result
+        }
+    };
+    // record the decision on the card, which also removes its buttons
+    let card = &query["message"];
+    let res = TELEGRAM
+        .call_api(
+            "editMessageText",
+            json!({
+                "chat_id": card["chat"]["id"],
+                "message_id": card["message_id"],
+                "text": format!(
+                    "{}\n\n{}",
+                    card["text"].as_str().unwrap_or_default(),
+                    truncate(&result, MAX_DECISION_CHARS)
+                ),
+            }),
+        )
+        .await;
+    match res {
+        // an earlier attempt already updated it
+        Err(err) if is_not_modified(&err) => {}
+        res => {
+            res.context("cannot update approval card")?;
+        }
+    }
+    Ok(result)
+}
+
+/// Records a user's feedback on a reply of the bot's, handing the conversation over to a human if
+/// they asked for one
+async fn process_rating(query: &Value, verb: &str, msg_id: i64) -> anyhow::Result<String> {
+    let reply = &query["message"];
+    // in groups, only the user the reply answered can rate it
+    if reply["chat"]["type"].as_str() != Some("private")
+        && query["from"]["id"].as_i64() != reply["reply_to_message"]["from"]["id"].as_i64()
+    {
+        return Ok("Only the person who asked can rate this answer.".to_owned());
+    }
+    let rating = match verb {
+        "helpful" => 1,
+        "unhelpful" => -1,
+        _ => 0,
+    };
+    let Some(convo_id) = DB.rate_msg(msg_id, rating).await? else {
+        return Ok("This answer can no longer be rated.".to_owned());
+    };
+    if rating == 0 {
+        log::warn!("convo {convo_id} escalated to a human at the user's request");
+        DB.set_convo_status(convo_id, ConvoStatus::Escalated)
+            .await?;
+        return Ok("Someone from our support team will follow up with you here.".to_owned());
+    }
+    Ok("Thanks for your feedback!".to_owned())
+}
+
+/// The buttons under a reply of the bot's, for the user to say how helpful it was
+fn feedback_keyboard(msg_id: i64) -> Value {
+    json!({"inline_keyboard": [[
+        {"text": "👍", "callback_data": format!("helpful:{msg_id}")},
+        {"text": "👎", "callback_data": format!("unhelpful:{msg_id}")},
+        {"text": "Talk to a human", "callback_data": format!("human:{msg_id}")},
+    ]]})
+}
+
+/// Posts a card with Approve / Reject buttons for an action to the admin's chat
+pub async fn send_approval_card(
+    admin_chat_id: i64,
+    approval_id: i64,
+    text: &str,
+) -> anyhow::Result<()> {
+    TELEGRAM
+        .call_api(
+            "sendMessage",
+            json!({
+                "chat_id": admin_chat_id,
+                "text": text,
+                "reply_markup": {"inline_keyboard": [[
+                    {"text": "Approve", "callback_data": format!("approve:{approval_id}")},
+                    {"text": "Reject", "callback_data": format!("reject:{approval_id}")},
+                ]]},
+            }),
+        )
+        .await
+        .context("cannot send approval card to telegram")?;
+    Ok(())
+}
+
+/// Sends a notification job's message to a Telegram chat in reply to the given message, and adds it
+/// to the conversation
+pub async fn send_followup(
+    job: &Job,
+    convo_id: i64,
+    chat_id: i64,
+    message_id: i64,
+    thread_id: Option<i64>,
+    text: &str,
+) -> anyhow::Result<()> {
+    let mut progress = match job.progress.clone() {
+        Some(progress) => progress,
+        None => {
+            DB.store_reply(
+                job.job_id,
+                None,
+                &Message {
+                    text: text.to_owned(),
+                    convo_id,
+                },
+                Platform::Telegram,
+                json!({ "chat_id": chat_id }),
+                None,
+            )
+            .await?
+        }
+    };
+    deliver(
+        job.job_id,
+        &mut progress,
+        chat_id,
+        &[],
+        message_id,
+        thread_id,
+        None,
+    )
+    .await
+    .context("cannot send follow-up to telegram")?;
+    DB.record_telegram_replies(chat_id, convo_id, &progress.sent_ids)
+        .await?;
+    Ok(())
+}
+
+/// Returns the Telegram file id, MIME type and size in bytes, if known, of the image in a message,
+/// if it has one: a photo, or an image sent as a file
+fn find_image(message: &Value) -> Option<(&str, &str, Option<u64>)> {
+    // photos come in several sizes, smallest first
+    if let Some(photo) = message["photo"].as_array().and_then(|sizes| sizes.last()) {
+        return Some((
+            photo["file_id"].as_str()?,
+            "image/jpeg",
+            photo["file_size"].as_u64(),
+        ));
+    }
+    let document = &message["document"];
+    let mime_type = document["mime_type"].as_str()?;
+    if mime_type.starts_with("image/") {
+        return Some((
+            document["file_id"].as_str()?,
+            mime_type,
+            document["file_size"].as_u64(),
+        ));
+    }
+    None
+}
+
+/// The image formats the vision model reads
+const IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+/// The largest file Telegram lets bots download
+const MAX_DOWNLOAD_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Downloads an image a user sent and asks the vision model what it shows. Returns None if the
+/// image cannot be read at all, e.g. because it is too large or in a format the model does not
+/// take, so that the rest of the message is still answered; failures that may pass are returned,
+/// so that the job is retried.
+async fn read_image(
+    file_id: &str,
+    mime_type: &str,
+    file_size: Option<u64>,
+    caption: &str,
+) -> anyhow::Result<Option<String>> {
+    if !IMAGE_TYPES.contains(&mime_type) {
+        log::warn!("not reading image {file_id} of type {mime_type}");
+        return Ok(None);
+    }
+    if file_size.is_some_and(|size| size > MAX_DOWNLOAD_BYTES) {
+        log::warn!("not reading image {file_id} of {file_size:?} bytes");
+        return Ok(None);
+    }
+    let result = async {
+        let image = TELEGRAM
+            .download_file(file_id)
+            .await
+            .context("cannot download image from telegram")?;
+        describe_image(&image, mime_type, caption)
+            .await
+            .context("cannot describe image")
+    }
+    .await;
+    match result {
+        Ok(description) => Ok(Some(description)),
+        Err(err) if is_permanent(&err) => {
+            log::warn!("cannot read image {file_id}: {:?}", err);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether an error from Telegram or OpenAI would come back if the same call was made again
+fn is_permanent(err: &anyhow::Error) -> bool {
+    if let Some(err) = err.downcast_ref::<TelegramError>() {
+        return err.error_code == 400;
+    }
+    err.downcast_ref::<OpenAiError>()
+        .is_some_and(|err| err.is_permanent())
+}
+
+/// A voice message or audio file in a Telegram message
+struct Audio<'a> {
+    file_id: &'a str,
+    /// a file name whose extension tells the transcription endpoint the audio's format
+    file_name: String,
+    /// in bytes, if Telegram says
+    file_size: Option<u64>,
+    /// in seconds, if Telegram says
+    duration: Option<u64>,
+}
+
+/// Returns the voice message or audio file in a message, if it has one
+fn find_audio(message: &Value) -> Option<Audio<'_>> {
+    let voice = &message["voice"];
+    if let Some(file_id) = voice["file_id"].as_str() {
+        return Some(Audio {
+            file_id,
+            // voice messages are always OGG files encoded with OPUS
+            file_name: "voice.ogg".to_owned(),
+            file_size: voice["file_size"].as_u64(),
+            duration: voice["duration"].as_u64(),
+        });
+    }
+    let audio = &message["audio"];
+    let file_id = audio["file_id"].as_str()?;
+    let file_name = match audio["file_name"].as_str() {
+        Some(file_name) => file_name.to_owned(),
+        None => {
+            let mime_type = audio["mime_type"].as_str().unwrap_or("audio/mpeg");
+            let extension = match mime_type.rsplit('/').next() {
+                Some("mpeg") | None => "mp3",
+                Some(subtype) => subtype,
+            };
+            format!("audio.{extension}")
+        }
+    };
+    Some(Audio {
+        file_id,
+        file_name,
+        file_size: audio["file_size"].as_u64(),
+        duration: audio["duration"].as_u64(),
+    })
+}
+
+/// The longest voice message or audio file the bot transcribes, in seconds
+const MAX_AUDIO_SECS: u64 = 10 * 60;
+
+/// What the bot says to a voice message it could not transcribe, when it came without a caption
+const UNTRANSCRIBED_REPLY: &str =
+    "Sorry, I could not understand your voice message. Could you type your question instead?";
+
+/// Downloads a voice message or audio file and transcribes it. Returns None if it cannot be
+/// transcribed at all, e.g. because it is too long or the endpoint rejects it; failures that may
+/// pass are returned, so that the job is retried.
+async fn transcribe(audio: &Audio<'_>) -> anyhow::Result<Option<String>> {
+    let file_id = audio.file_id;
+    if audio
+        .file_size
+        .is_some_and(|size| size > MAX_DOWNLOAD_BYTES)
+        || audio.duration.is_some_and(|secs| secs > MAX_AUDIO_SECS)
+    {
+        log::warn!(
+            "not transcribing {file_id} of {:?} bytes and {:?} seconds",
+            audio.file_size,
+            audio.duration
+        );
+        return Ok(None);
+    }
+    let result = async {
+        let bytes = TELEGRAM
+            .download_file(file_id)
+            .await
+            .context("cannot download voice message from telegram")?;
+        transcribe_audio(bytes, &audio.file_name)
+            .await
+            .context("cannot transcribe voice message")
+    }
+    .await;
+    match result {
+        Ok(transcript) => Ok(Some(transcript)),
+        Err(err) if is_permanent(&err) => {
+            log::warn!("cannot transcribe {file_id}: {:?}", err);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Shows the bot as typing in a chat until the returned task is dropped. Telegram clears the
+/// typing status after 5 seconds, so it is sent again every few seconds.
+fn keep_typing(chat_id: i64, thread_id: Option<i64>) -> smol::Task<()> {
+    let mut args = json!({"chat_id": chat_id, "action": "typing"});
+    if let Some(thread_id) = thread_id {
+        args["message_thread_id"] = json!(thread_id);
+    }
+    smolscale::spawn(async move {
+        loop {
+            if let Err(err) = TELEGRAM.call_api("sendChatAction", args.clone()).await {
+                log::warn!("cannot send typing status to chat {chat_id}: {:?}", err);
+            }
+            smol::Timer::after(TYPING_INTERVAL).await;
+        }
+    })
+}
+
+/// Sends a job's reply to a Telegram chat in reply to the given message, split into as many
+/// messages as it takes, and saves the id of each message with the job as soon as it is sent, so
+/// that a retry only sends the rest. If the bot answered before, in `old_message_ids`, the earlier
+/// answer's messages are edited in place, or replaced by new ones where they cannot be edited
+/// anymore, and any the reply does not need are deleted. The `reply_markup` goes under the last
+/// message.
+async fn deliver(
+    job_id: i64,
+    progress: &mut ReplyProgress,
+    chat_id: i64,
+    old_message_ids: &[i64],
+    reply_to_message_id: i64,
+    thread_id: Option<i64>,
+    reply_markup: Option<Value>,
+) -> anyhow::Result<()> {
+    let chunks = split_message(&progress.text, MAX_MESSAGE_CHARS);
+    let last = chunks.len().saturating_sub(1);
+    let done = progress.sent_ids.len();
+    for (i, chunk) in chunks.into_iter().enumerate().skip(done) {
+        let reply_markup = reply_markup.as_ref().filter(|_| i == last);
+        let edited = match old_message_ids.get(i) {
+            Some(&old_id) => {
+                let mut args = json!({"chat_id": chat_id, "message_id": old_id, "text": chunk});
+                if let Some(reply_markup) = reply_markup {
+                    args["reply_markup"] = reply_markup.clone();
+                }
+                match call_with_text("editMessageText", args, progress.in_convo).await {
+                    Ok(_) => Some(old_id),
+                    // Telegram refuses edits that change nothing, which leaves the message as it
+                    // should be
+                    Err(err) if is_not_modified(&err) => Some(old_id),
+                    // the message was deleted, or is too old to edit, so the part is sent anew
+                    Err(err)
+                        if err
+                            .downcast_ref::<TelegramError>()
+                            .is_some_and(|tg_err| tg_err.error_code == 400) =>
+                    {
+                        log::warn!("cannot edit message {old_id} in chat {chat_id}: {:?}", err);
+                        None
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            None => None,
+        };
+        let sent_id = match edited {
+            Some(id) => id,
+            None => {
+                let mut args = telegram_json(chunk, chat_id, reply_to_message_id, thread_id);
+                if let Some(reply_markup) = reply_markup {
+                    args["reply_markup"] = reply_markup.clone();
+                }
+                call_with_text("sendMessage", args, progress.in_convo).await?["message_id"]
+                    .as_i64()
+                    .context("telegram did not return the id of the sent message")?
+            }
+        };
+        progress.sent_ids.push(sent_id);
+        DB.save_job_progress(job_id, progress).await?;
+    }
+    for &old_id in old_message_ids.iter().skip(progress.sent_ids.len()) {
+        // a retry finds them already deleted
+        if let Err(err) = TELEGRAM
+            .call_api(
+                "deleteMessage",
+                json!({"chat_id": chat_id, "message_id": old_id}),
+            )
+            .await
+        {
+            log::warn!(
+                "cannot delete message {old_id} in chat {chat_id}: {:?}",
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Calls a Telegram method that sends or edits a message's text. With `markdown`, the text is
+/// rendered, and sent as it is if Telegram cannot parse the result.
+async fn call_with_text(method: &str, args: Value, markdown: bool) -> anyhow::Result<Value> {
+    if !markdown {
+        return TELEGRAM.call_api(method, args).await;
+    }
+    let mut formatted = args.clone();
+    formatted["text"] = json!(to_telegram_html(args["text"].as_str().unwrap_or_default()));
+    formatted["parse_mode"] = json!("HTML");
+    match TELEGRAM.call_api(method, formatted).await {
+        Ok(sent) => Ok(sent),
+        // a bad request may be formatting Telegram cannot parse; other failures have nothing to do
+        // with it, and an unchanged edit would only lose its formatting
+        Err(err)
+            if err
+                .downcast_ref::<TelegramError>()
+                .is_some_and(|tg_err| tg_err.error_code == 400)
+                && !is_not_modified(&err) =>
+        {
+            log::warn!(
+                "telegram rejected formatted message, sending it as plain text: {:?}",
+                err
+            );
+            TELEGRAM.call_api(method, args).await
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether Telegram refused an edit because it would not change the message
+fn is_not_modified(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<TelegramError>()
+        .is_some_and(|err| err.description.contains("message is not modified"))
+}
+
+async fn get_convo_id(message: &Value) -> anyhow::Result<i64> {
+    if message["chat"]["type"] == "private" {
+        message["chat"]["id"]
+            .as_i64()
+            .context("chat id could not be converted to i64")
+    } else {
+        let reply_to = &message["reply_to_message"];
+        // in forum topics, a message that replies to nothing in particular replies to the topic's
+        // first message
+        let replies_to_topic = message["is_topic_message"].as_bool() == Some(true)
+            && reply_to["message_id"].as_i64() == message["message_thread_id"].as_i64();
+        if !reply_to.is_null() && !replies_to_topic {
+            // the bot's replies are formatted, so their text can differ from what was stored
+            if let (Some(chat_id), Some(message_id)) = (
+                message["chat"]["id"].as_i64(),
+                reply_to["message_id"].as_i64(),
+            ) {
+                if let Some(id) = DB.telegram_reply_convo(chat_id, message_id).await? {
+                    return Ok(id);
+                }
+            }
+            if let Some(text) = reply_to["text"].as_str() {
+                if let Some(id) = DB.txt_to_id(text).await {
+                    return Ok(id);
+                }
             }
         }
         Ok(rand::random())
@@ -179,10 +1080,19 @@ async fn get_convo_id(update: Value) -> anyhow::Result<i64> {
 }
 
 // puts message into correct json format for telegram bot api
-fn telegram_json(msg: String, chat_id: i64, reply_to_message_id: i64) -> Value {
-    json!({
+fn telegram_json(
+    msg: String,
+    chat_id: i64,
+    reply_to_message_id: i64,
+    thread_id: Option<i64>,
+) -> Value {
+    let mut json = json!({
         "chat_id": chat_id,
         "text": msg,
         "reply_to_message_id": reply_to_message_id,
-    })
+    });
+    if let Some(thread_id) = thread_id {
+        json["message_thread_id"] = json!(thread_id);
+    }
+    json
 }